@@ -0,0 +1,242 @@
+// src/api_keys.rs
+//! API keys for backend services that call the HTTP interface without an
+//! IC identity. Each key acts on behalf of the principal that created it.
+//!
+//! A key is `<id>.<secret>`; only the SHA-256 of the secret is kept, so keys
+//! cannot be recovered from canister state.
+
+use crate::types::{ApiKeyInfo, ApiKeyScope};
+use crate::utils::is_under;
+use base64::prelude::*;
+use candid::Principal;
+use canister_error::Error;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// Most keys a single principal may hold at once.
+const MAX_KEYS_PER_OWNER: usize = 100;
+
+/// What a request wants to do with the asset it names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Debug)]
+struct StoredKey {
+    secret_hash: [u8; 32],
+    info: ApiKeyInfo,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ApiKeys {
+    keys: BTreeMap<u64, StoredKey>,
+    next_id: u64,
+}
+
+impl ApiKeys {
+    /// Issues a key for `owner` from 32 random bytes and returns it; it is
+    /// never shown again.
+    pub fn create(
+        &mut self,
+        owner: Principal,
+        name: String,
+        scopes: Vec<ApiKeyScope>,
+        expires_at: Option<u64>,
+        secret: &[u8],
+        now: u64,
+    ) -> Result<String, Error> {
+        validate_scopes(&scopes)?;
+        if self.list(&owner).len() >= MAX_KEYS_PER_OWNER {
+            return Err(Error::QuotaExceeded(format!(
+                "At most {} API keys per principal",
+                MAX_KEYS_PER_OWNER
+            )));
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.keys.insert(
+            id,
+            StoredKey {
+                secret_hash: Sha256::digest(secret).into(),
+                info: ApiKeyInfo {
+                    id,
+                    name,
+                    owner,
+                    scopes,
+                    created: now,
+                    expires_at,
+                },
+            },
+        );
+        Ok(format!("{}.{}", id, BASE64_URL_SAFE_NO_PAD.encode(secret)))
+    }
+
+    pub fn list(&self, owner: &Principal) -> Vec<ApiKeyInfo> {
+        self.keys
+            .values()
+            .filter(|key| key.info.owner == *owner)
+            .map(|key| key.info.clone())
+            .collect()
+    }
+
+    /// Revokes a key; only its owner may do so.
+    pub fn revoke(&mut self, id: u64, caller: &Principal) -> Result<(), Error> {
+        match self.keys.get(&id) {
+            Some(key) if key.info.owner == *caller => {
+                self.keys.remove(&id);
+                Ok(())
+            }
+            Some(_) => Err(Error::Unauthorized(format!(
+                "API key {} belongs to another principal",
+                id
+            ))),
+            None => Err(Error::NotFound(format!("API key {}", id))),
+        }
+    }
+
+    /// Drops keys that expired before `now`.
+    pub fn sweep_expired(&mut self, now: u64) {
+        self.keys.retain(|_, key| {
+            key.info
+                .expires_at
+                .is_none_or(|expires_at| expires_at > now)
+        });
+    }
+
+    /// The owner of `api_key` if the key is valid at `now` and its scopes
+    /// permit `access` to `asset_key`.
+    pub fn authenticate(
+        &self,
+        api_key: &str,
+        access: Access,
+        asset_key: &str,
+        now: u64,
+    ) -> Result<Principal, Error> {
+        let invalid = || Error::Unauthorized("Invalid API key".to_string());
+        let (id, secret) = api_key.trim().split_once('.').ok_or_else(invalid)?;
+        let id: u64 = id.parse().map_err(|_| invalid())?;
+        let secret = BASE64_URL_SAFE_NO_PAD
+            .decode(secret)
+            .map_err(|_| invalid())?;
+        let key = self.keys.get(&id).ok_or_else(invalid)?;
+        let secret_hash: [u8; 32] = Sha256::digest(&secret).into();
+        if secret_hash != key.secret_hash {
+            return Err(invalid());
+        }
+        if key
+            .info
+            .expires_at
+            .is_some_and(|expires_at| now >= expires_at)
+        {
+            return Err(Error::Unauthorized("API key has expired".to_string()));
+        }
+
+        if !permits(&key.info.scopes, access, asset_key) {
+            return Err(Error::Unauthorized(format!(
+                "API key {} does not permit {:?} access to {}",
+                id, access, asset_key
+            )));
+        }
+        Ok(key.info.owner)
+    }
+}
+
+fn validate_scopes(scopes: &[ApiKeyScope]) -> Result<(), Error> {
+    let grants_access = scopes
+        .iter()
+        .any(|scope| matches!(scope, ApiKeyScope::Read | ApiKeyScope::Write));
+    if !grants_access {
+        return Err(Error::invalid_argument(
+            "scopes",
+            "must include Read or Write",
+        ));
+    }
+    if scopes
+        .iter()
+        .any(|scope| matches!(scope, ApiKeyScope::Prefix(prefix) if prefix.is_empty()))
+    {
+        return Err(Error::invalid_argument(
+            "scopes",
+            "prefixes must not be empty",
+        ));
+    }
+    Ok(())
+}
+
+/// Keys with `Prefix` scopes are limited to asset keys under one of them,
+/// matching whole segments.
+fn permits(scopes: &[ApiKeyScope], access: Access, asset_key: &str) -> bool {
+    let has_access = scopes.iter().any(|scope| {
+        matches!(
+            (scope, access),
+            (ApiKeyScope::Read, Access::Read) | (ApiKeyScope::Write, Access::Write)
+        )
+    });
+    let mut prefixes = scopes
+        .iter()
+        .filter_map(|scope| match scope {
+            ApiKeyScope::Prefix(prefix) => Some(prefix),
+            _ => None,
+        })
+        .peekable();
+    let in_prefix = prefixes.peek().is_none() || prefixes.any(|prefix| is_under(asset_key, prefix));
+    has_access && in_prefix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_with(keys: &mut ApiKeys, scopes: Vec<ApiKeyScope>) -> String {
+        let owner = Principal::from_slice(&[1]);
+        keys.create(owner, "ci".to_string(), scopes, Some(100), &[9; 32], 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn prefixes_match_whole_segments() {
+        let mut keys = ApiKeys::default();
+        let key = key_with(
+            &mut keys,
+            vec![
+                ApiKeyScope::Read,
+                ApiKeyScope::Write,
+                ApiKeyScope::Prefix("/cars".to_string()),
+            ],
+        );
+        assert!(keys
+            .authenticate(&key, Access::Read, "/cars/a.png", 0)
+            .is_ok());
+        assert!(keys.authenticate(&key, Access::Write, "/cars", 0).is_ok());
+        assert!(keys
+            .authenticate(&key, Access::Read, "/carsharing/x", 0)
+            .is_err());
+        assert!(keys
+            .authenticate(&key, Access::Write, "/carsharing/x", 0)
+            .is_err());
+    }
+
+    #[test]
+    fn access_follows_scopes_and_expiry() {
+        let mut keys = ApiKeys::default();
+        let key = key_with(&mut keys, vec![ApiKeyScope::Read]);
+        assert!(keys
+            .authenticate(&key, Access::Read, "/any.png", 99)
+            .is_ok());
+        assert!(keys
+            .authenticate(&key, Access::Write, "/any.png", 0)
+            .is_err());
+        assert!(keys
+            .authenticate(&key, Access::Read, "/any.png", 100)
+            .is_err());
+
+        let (id, _) = key.split_once('.').unwrap();
+        let forged = format!("{}.{}", id, BASE64_URL_SAFE_NO_PAD.encode([8; 32]));
+        assert!(keys
+            .authenticate(&forged, Access::Read, "/any.png", 0)
+            .is_err());
+    }
+}
@@ -1,71 +1,419 @@
 // src/certification.rs
-use crate::state::State;
-use crate::types::{Asset, AssetKey};
-use crate::STATE;
+//! Response verification v2. Responses are certified with their status code
+//! and every header, either under their exact path or under a wildcard
+//! prefix that covers the paths without a response of their own. Responses
+//! echoing an allowed origin also certify the request's `Origin` header.
+
+use crate::types::HttpResponse;
+use base64::prelude::*;
 use ic_cdk::api::set_certified_data;
-use ic_certified_map::{AsHashTree, Hash, RbTree};
-use serde_cbor;
+use ic_http_certification::utils::more_specific_wildcards_for;
+use ic_http_certification::{
+    DefaultCelBuilder, DefaultResponseCertification, HttpCertification, HttpCertificationPath,
+    HttpCertificationTree, HttpCertificationTreeEntry, HttpRequest,
+    CERTIFICATE_EXPRESSION_HEADER_NAME,
+};
+use serde::Serialize;
 use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+pub type Hash = [u8; 32];
 
+/// A response as certified, including its `IC-CertificateExpression` header.
 #[derive(Clone)]
-pub struct AssetHashes {
-    pub hashes: RbTree<AssetKey, Hash>,
+pub struct CertifiedResponse {
+    pub response: HttpResponse,
+    entry: HttpCertificationTreeEntry<'static>,
+}
+
+impl CertifiedResponse {
+    /// Whether `response`, as built without the expression header, is the
+    /// one that was certified.
+    pub fn matches(&self, response: &HttpResponse) -> bool {
+        let (expression, headers) = self
+            .response
+            .headers
+            .split_last()
+            .expect("certified responses end with their expression header");
+        self.response.status_code == response.status_code
+            && expression.0 == CERTIFICATE_EXPRESSION_HEADER_NAME
+            && headers == response.headers.as_slice()
+            && *self.response.body == *response.body
+    }
 }
 
-impl Default for AssetHashes {
-    fn default() -> Self {
-        AssetHashes {
-            hashes: RbTree::new(),
+/// The responses certified under one path or prefix.
+#[derive(Clone)]
+struct Entries {
+    /// Served to any request.
+    any: CertifiedResponse,
+    /// Served only to requests from the origin each one echoes.
+    by_origin: HashMap<String, CertifiedResponse>,
+}
+
+impl Entries {
+    fn new(
+        path: HttpCertificationPath<'static>,
+        response: HttpResponse,
+        body_sha256: Option<Hash>,
+        by_origin: Vec<(String, HttpResponse)>,
+    ) -> Entries {
+        let body_sha256 = body_sha256.unwrap_or_else(|| Sha256::digest(&response.body).into());
+        Entries {
+            by_origin: by_origin
+                .into_iter()
+                .map(|(origin, response)| {
+                    let certified = certify(path.clone(), response, body_sha256, Some(&origin));
+                    (origin, certified)
+                })
+                .collect(),
+            any: certify(path, response, body_sha256, None),
         }
     }
+
+    fn iter(&self) -> impl Iterator<Item = &CertifiedResponse> {
+        std::iter::once(&self.any).chain(self.by_origin.values())
+    }
+
+    fn get(&self, origin: Option<&str>) -> &CertifiedResponse {
+        origin
+            .and_then(|origin| self.by_origin.get(origin))
+            .unwrap_or(&self.any)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct CertifiedResponses {
+    tree: HttpCertificationTree,
+    exact: HashMap<String, Entries>,
+    /// Wildcard responses by the path segments of their prefix.
+    wildcards: HashMap<Vec<Vec<u8>>, Entries>,
 }
 
-pub fn on_asset_change(mut asset_hashes: AssetHashes, key: &str, asset: &Asset) -> AssetHashes {
-    if let Some(encoding) = asset.encodings.get("identity") {
-        let hash = Hash::from(encoding.sha256);
-        asset_hashes.hashes.insert(key.to_string(), hash);
+impl CertifiedResponses {
+    pub fn root_hash(&self) -> Hash {
+        self.tree.root_hash()
+    }
+
+    /// Certifies `response` as the one served at exactly `path`, and each
+    /// response of `by_origin` as the one served to requests from its origin.
+    pub fn certify_exact(
+        &mut self,
+        path: &str,
+        response: HttpResponse,
+        body_sha256: Option<Hash>,
+        by_origin: Vec<(String, HttpResponse)>,
+    ) {
+        self.remove_exact(path);
+        let entries = Entries::new(
+            HttpCertificationPath::exact(path.to_string()),
+            response,
+            body_sha256,
+            by_origin,
+        );
+        for certified in entries.iter() {
+            self.tree.insert(&certified.entry);
+        }
+        self.exact.insert(path.to_string(), entries);
+    }
+
+    pub fn remove_exact(&mut self, path: &str) {
+        if let Some(entries) = self.exact.remove(path) {
+            for certified in entries.iter() {
+                self.tree.delete(&certified.entry);
+            }
+        }
+    }
+
+    /// Certifies `response`, and `by_origin` as for exact paths, for the
+    /// paths under `prefix` that have neither an exact response nor a more
+    /// specific wildcard.
+    pub fn certify_wildcard(
+        &mut self,
+        prefix: &str,
+        response: HttpResponse,
+        body_sha256: Option<Hash>,
+        by_origin: Vec<(String, HttpResponse)>,
+    ) {
+        let entries = Entries::new(
+            HttpCertificationPath::wildcard(prefix.to_string()),
+            response,
+            body_sha256,
+            by_origin,
+        );
+        if let Some(previous) = self.wildcards.remove(&segments(prefix)) {
+            for certified in previous.iter() {
+                self.tree.delete(&certified.entry);
+            }
+        }
+        for certified in entries.iter() {
+            self.tree.insert(&certified.entry);
+        }
+        self.wildcards.insert(segments(prefix), entries);
+    }
+
+    pub fn clear(&mut self) {
+        *self = CertifiedResponses::default();
+    }
+
+    /// The response certified for a request for `path` from `origin`: its
+    /// exact response, or else the one under the most specific wildcard
+    /// covering it.
+    pub fn get(&self, path: &str, origin: Option<&str>) -> Option<&CertifiedResponse> {
+        if let Some(entries) = self.exact.get(path) {
+            return Some(entries.get(origin));
+        }
+        more_specific_wildcards_for(&segments(path), &[])
+            .into_iter()
+            .find_map(|mut wildcard| {
+                // Drop the wildcard terminator
+                wildcard.pop();
+                self.wildcards.get(&wildcard)
+            })
+            .map(|entries| entries.get(origin))
+    }
+
+    /// The `IC-Certificate` header proving that `certified` is served at
+    /// `path`.
+    pub fn certificate_header(
+        &self,
+        certified: &CertifiedResponse,
+        path: &str,
+        certificate: &[u8],
+    ) -> Option<String> {
+        let witness = self.tree.witness(&certified.entry, path).ok()?;
+        Some(format!(
+            "certificate=:{}:, tree=:{}:, expr_path=:{}:, version=2",
+            BASE64_STANDARD.encode(certificate),
+            BASE64_STANDARD.encode(cbor(&witness)?),
+            BASE64_STANDARD.encode(cbor(&certified.entry.path.to_expr_path())?),
+        ))
     }
-    asset_hashes
 }
-pub fn get_root_hash(asset_hashes: &AssetHashes) -> Hash {
-    asset_hashes.hashes.root_hash()
+
+/// Certifies every header of a response. Of the request, only the `Origin`
+/// header is certified, and only for responses meant for one `origin`; the
+/// certified request has no query string.
+fn certify(
+    path: HttpCertificationPath<'static>,
+    mut response: HttpResponse,
+    body_sha256: Hash,
+    origin: Option<&str>,
+) -> CertifiedResponse {
+    let response_certification = DefaultResponseCertification::response_header_exclusions(vec![]);
+    let certification = match origin {
+        None => {
+            let expression = DefaultCelBuilder::response_only_certification()
+                .with_response_certification(response_certification)
+                .build();
+            add_expression_header(&mut response, expression.to_string());
+            HttpCertification::response_only(
+                &expression,
+                &without_body(&response),
+                Some(body_sha256),
+            )
+        }
+        Some(origin) => {
+            let expression = DefaultCelBuilder::full_certification()
+                .with_request_headers(vec!["Origin"])
+                .with_response_certification(response_certification)
+                .build();
+            add_expression_header(&mut response, expression.to_string());
+            let request = HttpRequest {
+                method: "GET".to_string(),
+                url: "/".to_string(),
+                headers: vec![("Origin".to_string(), origin.to_string())],
+                body: vec![],
+            };
+            HttpCertification::full(
+                &expression,
+                &request,
+                &without_body(&response),
+                Some(body_sha256),
+            )
+        }
+    }
+    .expect("the expression header was just added");
+    CertifiedResponse {
+        response,
+        entry: HttpCertificationTreeEntry::new(path, certification),
+    }
 }
 
-// pub fn get_root_hash() -> Hash {
-//     STATE.with(|state| {
-//         let asset_hashes = &state.borrow().asset_hashes;
-//         reconstruct(&asset_hashes.hashes.as_hash_tree())
-//     })
-// }
+fn add_expression_header(response: &mut HttpResponse, expression: String) {
+    response
+        .headers
+        .push((CERTIFICATE_EXPRESSION_HEADER_NAME.to_string(), expression));
+}
 
-pub fn update_certified_data(asset_hashes: &AssetHashes) {
-    let root_hash = asset_hashes.hashes.root_hash();
-    set_certified_data(&root_hash);
+/// `response` as the certification crate takes it, with the body hashed
+/// separately.
+fn without_body(response: &HttpResponse) -> ic_http_certification::HttpResponse {
+    ic_http_certification::HttpResponse {
+        status_code: response.status_code,
+        headers: response.headers.clone(),
+        body: vec![],
+        upgrade: None,
+    }
 }
-pub fn create_asset_witness(key: &str) -> Vec<u8> {
-    STATE.with(|state| {
-        let asset_hashes = &state.borrow().asset_hashes;
-        let witness = asset_hashes.hashes.witness(key.as_bytes());
-        // Convert the HashTree to Vec<u9> using CBOR encoding
-        serde_cbor::to_vec(&witness).unwrap_or_default()
-    })
+
+/// Path segments as the certification tree orders them: a trailing `/`
+/// adds an empty segment, so `/` itself is a single empty segment.
+fn segments(path: &str) -> Vec<Vec<u8>> {
+    let mut segments: Vec<Vec<u8>> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| segment.as_bytes().to_vec())
+        .collect();
+    if path.ends_with('/') {
+        segments.push(vec![]);
+    }
+    segments
 }
 
-pub fn verify_asset_integrity(key: &str, content: &[u8]) -> bool {
-    STATE.with(|state| {
-        let asset_hashes = &state.borrow().asset_hashes;
-        if let Some(stored_hash) = asset_hashes.hashes.get(key.as_bytes()) {
-            let mut hasher = Sha256::new();
-            hasher.update(content);
-            let hash_result = hasher.finalize();
-
-            // Convert hash_result to [u8; 32]
-            let computed_hash: [u8; 32] = hash_result.into(); // Explicit conversion
-
-            // Assuming stored_hash is of type [u8; 32] or similar
-            computed_hash == *stored_hash
-        } else {
-            false
+/// Self-describing CBOR, as the `IC-Certificate` header expects.
+fn cbor(value: &impl Serialize) -> Option<Vec<u8>> {
+    let mut serializer = serde_cbor::Serializer::new(vec![]);
+    serializer.self_describe().ok()?;
+    value.serialize(&mut serializer).ok()?;
+    Some(serializer.into_inner())
+}
+
+pub fn update_certified_data(certified_responses: &CertifiedResponses) {
+    set_certified_data(&certified_responses.root_hash());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rc_bytes::RcBytes;
+    use ic_certification::HashTree;
+    use serde_bytes::ByteBuf;
+
+    fn response(status_code: u16, body: &str) -> HttpResponse {
+        HttpResponse {
+            status_code,
+            headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+            body: RcBytes::from(ByteBuf::from(body)),
+            upgrade: None,
+            streaming_strategy: None,
         }
-    })
+    }
+
+    fn body_at<'a>(responses: &'a CertifiedResponses, path: &str) -> Option<&'a [u8]> {
+        responses
+            .get(path, None)
+            .map(|certified| &*certified.response.body)
+    }
+
+    /// Decodes the witness in an `IC-Certificate` header.
+    fn witness(header: &str) -> HashTree {
+        let tree = header
+            .split("tree=:")
+            .nth(1)
+            .and_then(|rest| rest.split(':').next())
+            .unwrap();
+        serde_cbor::from_slice(&BASE64_STANDARD.decode(tree).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn exact_responses_win_over_wildcards() {
+        let mut responses = CertifiedResponses::default();
+        responses.certify_wildcard("/", response(404, "root"), None, vec![]);
+        responses.certify_wildcard("/cars", response(404, "cars"), None, vec![]);
+        responses.certify_exact("/cars/a.png", response(200, "a"), None, vec![]);
+
+        assert_eq!(body_at(&responses, "/cars/a.png"), Some(&b"a"[..]));
+        assert_eq!(body_at(&responses, "/cars/b.png"), Some(&b"cars"[..]));
+        assert_eq!(body_at(&responses, "/cars"), Some(&b"cars"[..]));
+        assert_eq!(body_at(&responses, "/carsharing"), Some(&b"root"[..]));
+        assert_eq!(body_at(&responses, "/"), Some(&b"root"[..]));
+
+        responses.remove_exact("/cars/a.png");
+        assert_eq!(body_at(&responses, "/cars/a.png"), Some(&b"cars"[..]));
+    }
+
+    #[test]
+    fn trailing_slash_prefixes_cover_only_paths_below() {
+        let mut responses = CertifiedResponses::default();
+        responses.certify_wildcard("/", response(404, "root"), None, vec![]);
+        responses.certify_wildcard("/docs/", response(404, "docs"), None, vec![]);
+
+        assert_eq!(body_at(&responses, "/docs/intro"), Some(&b"docs"[..]));
+        assert_eq!(body_at(&responses, "/docs/"), Some(&b"docs"[..]));
+        assert_eq!(body_at(&responses, "/docs"), Some(&b"root"[..]));
+    }
+
+    #[test]
+    fn lookups_without_certified_responses_fail() {
+        let mut responses = CertifiedResponses::default();
+        responses.certify_exact("/a.png", response(200, "a"), None, vec![]);
+        assert!(responses.get("/b.png", None).is_none());
+    }
+
+    #[test]
+    fn matches_only_the_certified_response() {
+        let mut responses = CertifiedResponses::default();
+        responses.certify_exact("/a.png", response(200, "a"), None, vec![]);
+        let certified = responses.get("/a.png", None).unwrap();
+
+        assert!(certified.matches(&response(200, "a")));
+        assert!(!certified.matches(&response(404, "a")));
+        assert!(!certified.matches(&response(200, "b")));
+        let mut extra_header = response(200, "a");
+        extra_header
+            .headers
+            .push(("Cache-Control".to_string(), "no-cache".to_string()));
+        assert!(!certified.matches(&extra_header));
+    }
+
+    #[test]
+    fn echoed_origins_are_certified_per_origin() {
+        let origin = "https://app.example.com";
+        let mut echoed = response(200, "a");
+        echoed.headers.push((
+            "Access-Control-Allow-Origin".to_string(),
+            origin.to_string(),
+        ));
+        let mut responses = CertifiedResponses::default();
+        let empty_root_hash = responses.root_hash();
+        responses.certify_exact(
+            "/a.png",
+            response(200, "a"),
+            None,
+            vec![(origin.to_string(), echoed.clone())],
+        );
+
+        for (origin, expected) in [
+            (Some(origin), &echoed),
+            (Some("https://other.example.com"), &response(200, "a")),
+            (None, &response(200, "a")),
+        ] {
+            let certified = responses.get("/a.png", origin).unwrap();
+            assert!(certified.matches(expected));
+            let header = responses
+                .certificate_header(certified, "/a.png", b"certificate")
+                .unwrap();
+            assert_eq!(witness(&header).digest(), responses.root_hash());
+        }
+
+        responses.remove_exact("/a.png");
+        assert_eq!(responses.root_hash(), empty_root_hash);
+    }
+
+    #[test]
+    fn witnesses_prove_the_root_hash() {
+        let mut responses = CertifiedResponses::default();
+        responses.certify_wildcard("/", response(404, "root"), None, vec![]);
+        responses.certify_exact("/a.png", response(200, "a"), None, vec![]);
+
+        for path in ["/a.png", "/missing/b.png"] {
+            let certified = responses.get(path, None).unwrap();
+            let header = responses
+                .certificate_header(certified, path, b"certificate")
+                .unwrap();
+            assert!(header.ends_with("version=2"));
+            assert_eq!(witness(&header).digest(), responses.root_hash());
+        }
+    }
 }
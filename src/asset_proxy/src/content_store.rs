@@ -0,0 +1,84 @@
+// src/content_store.rs
+//! Content-addressed blob storage shared by all asset encodings.
+
+use crate::rc_bytes::RcBytes;
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
+
+pub type ContentHash = [u8; 32];
+
+/// A stored blob together with the number of encodings referencing it.
+#[derive(Clone, Debug)]
+pub struct Blob {
+    pub content: RcBytes,
+    pub ref_count: usize,
+}
+
+/// Blobs keyed by their SHA-256, so identical uploads share one copy.
+#[derive(Clone, Debug, Default)]
+pub struct ContentStore {
+    pub blobs: HashMap<ContentHash, Blob>,
+}
+
+impl ContentStore {
+    /// Takes a reference to the blob with the given hash, inserting `content`
+    /// if it is not stored yet, and returns the shared bytes.
+    pub fn acquire(&mut self, hash: ContentHash, content: ByteBuf) -> RcBytes {
+        let blob = self.blobs.entry(hash).or_insert_with(|| Blob {
+            content: RcBytes::from(content),
+            ref_count: 0,
+        });
+        blob.ref_count += 1;
+        blob.content.clone()
+    }
+
+    /// Takes another reference to a blob that is already stored.
+    pub fn retain(&mut self, hash: &ContentHash) {
+        if let Some(blob) = self.blobs.get_mut(hash) {
+            blob.ref_count += 1;
+        }
+    }
+
+    /// Drops a reference to the blob with the given hash, freeing the bytes
+    /// once nothing refers to it anymore.
+    pub fn release(&mut self, hash: &ContentHash) {
+        if let Some(blob) = self.blobs.get_mut(hash) {
+            blob.ref_count = blob.ref_count.saturating_sub(1);
+            if blob.ref_count == 0 {
+                self.blobs.remove(hash);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_identical_content() {
+        let mut store = ContentStore::default();
+        let first = store.acquire([1; 32], ByteBuf::from(b"abc".to_vec()));
+        let second = store.acquire([1; 32], ByteBuf::from(b"ignored".to_vec()));
+        assert_eq!(&*first, b"abc");
+        assert_eq!(&*second, b"abc");
+        assert_eq!(store.blobs.len(), 1);
+        assert_eq!(store.blobs[&[1; 32]].ref_count, 2);
+    }
+
+    #[test]
+    fn frees_content_with_its_last_reference() {
+        let mut store = ContentStore::default();
+        store.acquire([1; 32], ByteBuf::from(b"abc".to_vec()));
+        store.retain(&[1; 32]);
+        store.release(&[1; 32]);
+        assert_eq!(store.blobs[&[1; 32]].ref_count, 1);
+        store.release(&[1; 32]);
+        assert!(store.blobs.is_empty());
+
+        // Unknown hashes are ignored rather than resurrected
+        store.retain(&[1; 32]);
+        store.release(&[2; 32]);
+        assert!(store.blobs.is_empty());
+    }
+}
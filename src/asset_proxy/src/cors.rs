@@ -0,0 +1,157 @@
+// src/cors.rs
+//! Cross-origin access to served assets, configured per path prefix.
+
+use crate::types::CorsPolicy;
+use crate::utils::is_under;
+use canister_error::Error;
+use std::collections::BTreeMap;
+
+/// Policies by path prefix; the longest prefix matching a path applies.
+#[derive(Clone, Debug, Default)]
+pub struct CorsPolicies {
+    policies: BTreeMap<String, CorsPolicy>,
+}
+
+impl CorsPolicies {
+    /// Sets the policy for `prefix`, or removes it when `policy` is `None`.
+    pub fn set(&mut self, prefix: String, policy: Option<CorsPolicy>) -> Result<(), Error> {
+        if !prefix.starts_with('/') {
+            return Err(Error::invalid_argument("prefix", "must start with '/'"));
+        }
+        match policy {
+            Some(policy) => {
+                validate_policy(&policy)?;
+                self.policies.insert(prefix, policy);
+            }
+            None => {
+                self.policies.remove(&prefix);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<(String, CorsPolicy)> {
+        self.policies
+            .iter()
+            .map(|(prefix, policy)| (prefix.clone(), policy.clone()))
+            .collect()
+    }
+
+    pub fn prefixes(&self) -> impl Iterator<Item = &String> {
+        self.policies.keys()
+    }
+
+    pub fn policy_for(&self, path: &str) -> Option<&CorsPolicy> {
+        self.policies
+            .iter()
+            .rev()
+            .find(|(prefix, _)| is_under(path, prefix))
+            .map(|(_, policy)| policy)
+    }
+}
+
+fn validate_policy(policy: &CorsPolicy) -> Result<(), Error> {
+    for origin in &policy.allowed_origins {
+        let valid = origin == "*"
+            || origin
+                .split_once("://")
+                .is_some_and(|(scheme, host)| !scheme.is_empty() && !host.is_empty());
+        if !valid || origin.ends_with('/') {
+            return Err(Error::invalid_argument(
+                "allowed_origins",
+                format!("{:?} is neither \"*\" nor a scheme://host origin", origin),
+            ));
+        }
+    }
+    for method in &policy.allowed_methods {
+        if method.is_empty() || !method.chars().all(|c| c.is_ascii_uppercase()) {
+            return Err(Error::invalid_argument(
+                "allowed_methods",
+                format!("{:?} is not an uppercase method name", method),
+            ));
+        }
+    }
+    for header in &policy.allowed_headers {
+        if header.is_empty()
+            || !header
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return Err(Error::invalid_argument(
+                "allowed_headers",
+                format!("{:?} is not a header name", header),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The `Access-Control-Allow-Origin` value for a request from `origin`,
+/// if the policy allows it.
+fn allowed_origin(policy: &CorsPolicy, origin: Option<&str>) -> Option<String> {
+    if allows_any_origin(policy) {
+        return Some("*".to_string());
+    }
+    let origin = origin?;
+    policy
+        .allowed_origins
+        .iter()
+        .any(|allowed| allowed == origin)
+        .then(|| origin.to_string())
+}
+
+fn allows_any_origin(policy: &CorsPolicy) -> bool {
+    policy.allowed_origins.iter().any(|allowed| allowed == "*")
+}
+
+/// The origins whose requests get a response of their own, echoing them.
+pub fn echoed_origins(policy: &CorsPolicy) -> impl Iterator<Item = &String> {
+    let echoes = !allows_any_origin(policy);
+    policy.allowed_origins.iter().filter(move |_| echoes)
+}
+
+/// Headers every response under the policy carries, whatever its origin.
+pub fn response_headers(policy: &CorsPolicy) -> Vec<(String, String)> {
+    if allows_any_origin(policy) {
+        vec![("Access-Control-Allow-Origin".to_string(), "*".to_string())]
+    } else {
+        // Echoed origins make the response differ per requesting origin
+        vec![("Vary".to_string(), "Origin".to_string())]
+    }
+}
+
+/// The echoed `Access-Control-Allow-Origin` header for a request from
+/// `origin`, if the policy lists it explicitly.
+pub fn origin_header(policy: &CorsPolicy, origin: Option<&str>) -> Option<(String, String)> {
+    if allows_any_origin(policy) {
+        return None;
+    }
+    allowed_origin(policy, origin)
+        .map(|allowed| ("Access-Control-Allow-Origin".to_string(), allowed))
+}
+
+/// Headers for an `OPTIONS` preflight from `origin`, or `None` if the
+/// origin is not allowed.
+pub fn preflight_headers(
+    policy: &CorsPolicy,
+    origin: Option<&str>,
+) -> Option<Vec<(String, String)>> {
+    allowed_origin(policy, origin)?;
+
+    let mut headers = response_headers(policy);
+    headers.extend(origin_header(policy, origin));
+    headers.push((
+        "Access-Control-Allow-Methods".to_string(),
+        policy.allowed_methods.join(", "),
+    ));
+    if !policy.allowed_headers.is_empty() {
+        headers.push((
+            "Access-Control-Allow-Headers".to_string(),
+            policy.allowed_headers.join(", "),
+        ));
+    }
+    if let Some(max_age) = policy.max_age_seconds {
+        headers.push(("Access-Control-Max-Age".to_string(), max_age.to_string()));
+    }
+    Some(headers)
+}
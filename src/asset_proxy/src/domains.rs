@@ -0,0 +1,66 @@
+// src/domains.rs
+//! Custom domains the canister is served from.
+//!
+//! Boundary nodes only route a custom domain to a canister that lists it in
+//! a certified `/.well-known/ic-domains` file, one domain per line.
+
+use canister_error::Error;
+
+/// HTTP path of the domain list; no asset may be stored under it.
+pub const IC_DOMAINS_PATH: &str = "/.well-known/ic-domains";
+
+/// Domains whose `raw.` subdomain serves responses without certification.
+const RAW_DOMAIN_SUFFIXES: &[&str] = &["icp0.io", "ic0.app"];
+
+/// Longest domain name allowed by DNS.
+const MAX_DOMAIN_LENGTH: usize = 253;
+
+/// Accepts bare host names such as `assets.example.com`, without a scheme,
+/// port or path.
+pub fn validate_domain(domain: &str) -> Result<(), Error> {
+    if domain.is_empty() || domain.len() > MAX_DOMAIN_LENGTH {
+        return Err(Error::invalid_argument(
+            "domain",
+            format!("must be between 1 and {} bytes long", MAX_DOMAIN_LENGTH),
+        ));
+    }
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    };
+    if !domain.contains('.') || !domain.split('.').all(valid_label) {
+        return Err(Error::invalid_argument(
+            "domain",
+            format!("{:?} is not a lowercase host name", domain),
+        ));
+    }
+    Ok(())
+}
+
+/// If `host` is a raw domain such as `<canister>.raw.icp0.io` or
+/// `<canister>.raw.localhost:4943`, the certified host it corresponds to.
+pub fn certified_host(host: &str) -> Option<String> {
+    let (canister, rest) = host.split_once('.')?;
+    let domain = rest.strip_prefix("raw.")?;
+    let known = RAW_DOMAIN_SUFFIXES.contains(&domain) || is_local_host(domain);
+    known.then(|| format!("{}.{}", canister, domain))
+}
+
+/// Whether `host` is a local replica, which is served over plain HTTP.
+pub fn is_local_host(host: &str) -> bool {
+    let name = host.split(':').next().unwrap_or_default();
+    name == "localhost" || name.ends_with(".localhost") || name == "127.0.0.1"
+}
+
+/// Body of the `/.well-known/ic-domains` file.
+pub fn ic_domains_file(domains: &[String]) -> String {
+    domains
+        .iter()
+        .map(|domain| format!("{}\n", domain))
+        .collect()
+}
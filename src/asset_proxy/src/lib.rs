@@ -1,71 +1,523 @@
 // src/lib.rs
-use candid::CandidType;
-use candid::{candid_method, Principal};
-use ic_cdk::api::{call::CallResult, data_certificate, set_certified_data, time, trap};
-use ic_cdk_macros::{init, post_upgrade, query, update};
+use candid::Principal;
+use ic_cdk::api::call::{
+    accept_message, arg_data, arg_data_raw_size, method_name, ArgDecoderConfig,
+};
+use ic_cdk::api::management_canister::main::{canister_info, raw_rand, CanisterInfoRequest};
+use ic_cdk::api::{caller, data_certificate, is_controller, time, trap};
+use ic_cdk_macros::{init, inspect_message, post_upgrade, query, update};
+use ic_cdk_timers::{set_timer, set_timer_interval};
+use serde_bytes::ByteBuf;
 use std::cell::RefCell;
 use std::collections::BTreeMap; // Import BTreeMap, which is Rust's implementation of RBTree
+use std::time::Duration;
+mod api_keys;
 mod certification;
+mod content_store;
+mod cors;
+mod domains;
+mod listing;
+mod metrics;
+mod multipart;
+mod quota;
+mod rate_limit;
 mod rc_bytes;
+mod routing;
+mod security_headers;
+mod signed_url;
+mod stats;
 // mod http;
 mod state;
 mod types;
 mod utils;
+mod versions;
 
-use crate::certification::get_root_hash;
+use crate::signed_url::SigningSecret;
 use crate::state::State;
-use crate::types::{AssetKey, HttpRequest, HttpResponse, StoreArg};
+use crate::types::{
+    ApiKeyInfo, AssetKey, CorsPolicy, CreateApiKeyArg, HttpRequest, HttpResponse, MetricsConfig,
+    QuotaConfig, RateLimitConfig, RoutingRule, SecurityHeaderPolicy, Stats, StoreArg, StoreResult,
+    Usage, VersionInfo, Visibility,
+};
+use crate::utils::{expiry_time, validate_key};
+use canister_error::Error;
 use types::Asset;
 
 thread_local! {
     static STATE: RefCell<State> = RefCell::new(State::default());
-    static ASSETS: RefCell<BTreeMap<String, Asset>> = RefCell::new(BTreeMap::new());
+    static ASSETS: RefCell<BTreeMap<String, Asset>> = const { RefCell::new(BTreeMap::new()) };
 }
 
+/// How often expired assets are swept.
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
 #[init]
 fn init() {
-    STATE.with(|state| {
-        // Initialize the state if needed
-    });
+    certify_responses();
+    record_install();
+    start_expiry_sweeper();
 }
 
 #[post_upgrade]
 fn post_upgrade() {
+    certify_responses();
+    record_install();
+    start_expiry_sweeper();
+}
+
+/// Certifies the responses served before any asset is stored, such as the
+/// 404 for every path.
+fn certify_responses() {
     STATE.with(|state| {
-        // Handle post-upgrade logic if needed
+        let mut state = state.borrow_mut();
+        state.certify_all();
+        certification::update_certified_data(&state.certified_responses);
     });
 }
 
+/// Records when the current module was installed. Its hash can only be
+/// looked up with an inter-canister call, which `init` and `post_upgrade`
+/// cannot make, so that happens in a timer right after.
+fn record_install() {
+    STATE.with(|state| state.borrow_mut().last_upgrade = time());
+    set_timer(Duration::ZERO, || ic_cdk::spawn(fetch_module_hash()));
+}
+
+async fn fetch_module_hash() {
+    let arg = CanisterInfoRequest {
+        canister_id: ic_cdk::id(),
+        num_requested_changes: None,
+    };
+    // On failure `stats` reports no hash until the next upgrade
+    if let Ok((info,)) = canister_info(arg).await {
+        STATE.with(|state| state.borrow_mut().module_hash = info.module_hash.map(ByteBuf::from));
+    }
+}
+
+fn start_expiry_sweeper() {
+    set_timer_interval(EXPIRY_SWEEP_INTERVAL, || {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            if state.sweep_expired(time()).is_empty() {
+                return;
+            }
+
+            // Update certified data
+            certification::update_certified_data(&state.certified_responses);
+        })
+    });
+}
+
+/// Upper bound on the encoded arguments of any update other than `store`.
+const MAX_ARG_SIZE: usize = 64 * 1024;
+/// Room for the Candid envelope and metadata around uploaded content.
+const STORE_ARG_OVERHEAD: usize = 64 * 1024;
+
+#[inspect_message]
+fn inspect_message() {
+    let caller = caller();
+    let method = method_name();
+    // HTTP gateways upgrade requests on behalf of anonymous users
+    if caller == Principal::anonymous() && method != "http_request_update" {
+        return;
+    }
+
+    // Reject ingress that would fail anyway before it costs cycles
+    let arg_size = arg_data_raw_size();
+    let allowed = match method.as_str() {
+        "store" => {
+            within_upload_size(arg_size) && {
+                let (arg,): (StoreArg,) = arg_data(ArgDecoderConfig::default());
+                STATE.with(|state| state.borrow().inspect_store(&arg, &caller, time()))
+            }
+        }
+        // POST and PUT uploads through the HTTP gateway carry the content
+        "http_request_update" => {
+            if STATE.with(|state| state.borrow().http_uploads_enabled) {
+                within_upload_size(arg_size)
+            } else {
+                arg_size <= MAX_ARG_SIZE
+            }
+        }
+        "delete" => {
+            arg_size <= MAX_ARG_SIZE && {
+                let (key,): (AssetKey,) = arg_data(ArgDecoderConfig::default());
+                validate_key(&key).is_ok()
+            }
+        }
+        "set_visibility" => {
+            arg_size <= MAX_ARG_SIZE && {
+                let (key, _): (AssetKey, Visibility) = arg_data(ArgDecoderConfig::default());
+                validate_key(&key).is_ok()
+            }
+        }
+        "set_quota_config"
+        | "set_rate_limit"
+        | "set_provision_canister"
+        | "grant_role"
+        | "revoke_role"
+        | "set_max_versions"
+        | "set_custom_domains"
+        | "set_cors_policy"
+        | "set_http_uploads_enabled"
+        | "set_routing_rules"
+        | "set_fallback"
+        | "set_directory_listing"
+        | "set_security_headers"
+        | "set_security_header_override"
+        | "set_metrics_config" => arg_size <= MAX_ARG_SIZE && is_controller(&caller),
+        _ => arg_size <= MAX_ARG_SIZE,
+    };
+    if allowed {
+        accept_message();
+    }
+}
+
+/// Whether an argument of `arg_size` bytes can carry content within the
+/// configured maximum asset size.
+fn within_upload_size(arg_size: usize) -> bool {
+    match STATE.with(|state| state.borrow().quotas.config.max_asset_size) {
+        Some(max) => arg_size <= max as usize + STORE_ARG_OVERHEAD,
+        None => true,
+    }
+}
+
 #[update]
-fn store(arg: StoreArg) -> String {
+fn store(arg: StoreArg) -> Result<StoreResult, Error> {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        if let Err(msg) = state.store(arg.clone(), ic_cdk::api::time()) {
-            ic_cdk::trap(&msg);
+        let (key, content_encoding) = (arg.key.clone(), arg.content_encoding.clone());
+        if let Err(err) = state.store(arg, caller(), time()) {
+            state.metrics.record_error(&err);
+            return Err(err);
         }
+        let result = state.store_result(&key, &content_encoding, &ic_cdk::id())?;
+
+        // Update certified data
+        certification::update_certified_data(&state.certified_responses);
+        Ok(result)
+    })
+}
+
+#[update]
+fn delete(key: AssetKey) -> Result<(), Error> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.delete(&key, caller())?;
+
+        // Update certified data
+        certification::update_certified_data(&state.certified_responses);
+        Ok(())
+    })
+}
+
+/// Keeps the given assets from expiring, e.g. once a collection that
+/// references them has been committed. The provision canister names the
+/// collection's `owner`; other callers pin their own assets.
+#[update]
+fn pin_assets(keys: Vec<AssetKey>, owner: Option<Principal>) -> Result<(), Error> {
+    STATE.with(|state| state.borrow_mut().pin(&keys, owner, caller()))
+}
+
+#[query]
+fn list_versions(key: AssetKey) -> Result<Vec<VersionInfo>, Error> {
+    STATE.with(|state| state.borrow().list_versions(&key, &caller()))
+}
+
+#[query]
+fn get_version(key: AssetKey, version: u64) -> Result<Vec<u8>, Error> {
+    STATE.with(|state| state.borrow().get_version(&key, version, &caller()))
+}
+
+#[update]
+fn rollback(key: AssetKey, version: u64) -> Result<(), Error> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.rollback(&key, version, caller(), time())?;
+
+        // Update certified data
+        certification::update_certified_data(&state.certified_responses);
+        Ok(())
+    })
+}
+
+#[query]
+fn get_max_versions() -> u64 {
+    STATE.with(|state| state.borrow().versions.max_versions as u64)
+}
+
+#[update]
+fn set_max_versions(max_versions: u64) -> Result<(), Error> {
+    ensure_controller()?;
+    STATE.with(|state| state.borrow_mut().set_max_versions(max_versions as usize));
+    Ok(())
+}
+
+#[query]
+fn list_my_assets() -> Vec<AssetKey> {
+    STATE.with(|state| state.borrow().list_assets_owned_by(&caller()))
+}
+
+#[query]
+fn get_usage(principal: Principal) -> Usage {
+    STATE.with(|state| state.borrow().get_usage(&principal))
+}
+
+#[query]
+fn get_quota_config() -> QuotaConfig {
+    STATE.with(|state| state.borrow().quotas.config.clone())
+}
+
+#[update]
+fn set_quota_config(config: QuotaConfig) -> Result<(), Error> {
+    ensure_controller()?;
+    STATE.with(|state| state.borrow_mut().quotas.config = config);
+    Ok(())
+}
+
+#[query]
+fn get_rate_limit() -> Option<RateLimitConfig> {
+    STATE.with(|state| state.borrow().rate_limiter.config.clone())
+}
+
+#[update]
+fn set_rate_limit(config: Option<RateLimitConfig>) -> Result<(), Error> {
+    ensure_controller()?;
+    STATE.with(|state| state.borrow_mut().rate_limiter.config = config);
+    Ok(())
+}
+
+#[query]
+fn get_custom_domains() -> Vec<String> {
+    STATE.with(|state| state.borrow().custom_domains.clone())
+}
+
+/// Replaces the custom domains the canister is served from. The first one
+/// becomes the primary domain used in asset URLs.
+#[update]
+fn set_custom_domains(domains: Vec<String>) -> Result<(), Error> {
+    ensure_controller()?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.set_custom_domains(domains)?;
 
         // Update certified data
-        let asset_hashes = state.asset_hashes.clone();
-        drop(state); // Release the mutable borrow
+        certification::update_certified_data(&state.certified_responses);
+        Ok(())
+    })
+}
+
+#[query]
+fn list_cors_policies() -> Vec<(String, CorsPolicy)> {
+    STATE.with(|state| state.borrow().cors.list())
+}
 
-        certification::update_certified_data(&asset_hashes);
+/// Sets the CORS policy for assets under `prefix`, or removes it when
+/// `policy` is omitted.
+#[update]
+fn set_cors_policy(prefix: String, policy: Option<CorsPolicy>) -> Result<(), Error> {
+    ensure_controller()?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.set_cors_policy(prefix, policy)?;
 
-        // Generate and return the asset URL
-        format!("https://{}.icp0.io/{}", ic_cdk::id().to_text(), arg.key)
+        // Update certified data
+        certification::update_certified_data(&state.certified_responses);
+        Ok(())
     })
 }
 
 #[query]
-fn retrieve(key: AssetKey) -> Vec<u8> {
-    STATE.with(|state| match state.borrow().retrieve(&key) {
-        Ok(content) => content,
-        Err(msg) => trap(&msg),
+fn get_http_uploads_enabled() -> bool {
+    STATE.with(|state| state.borrow().http_uploads_enabled)
+}
+
+/// Enables or disables uploads through `POST` and `PUT` requests.
+#[update]
+fn set_http_uploads_enabled(enabled: bool) -> Result<(), Error> {
+    ensure_controller()?;
+    STATE.with(|state| state.borrow_mut().http_uploads_enabled = enabled);
+    Ok(())
+}
+
+#[query]
+fn list_routing_rules() -> Vec<RoutingRule> {
+    STATE.with(|state| state.borrow().routing.list())
+}
+
+/// Replaces the redirect and rewrite rules applied before asset lookup.
+#[update]
+fn set_routing_rules(rules: Vec<RoutingRule>) -> Result<(), Error> {
+    ensure_controller()?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.set_routing_rules(rules)?;
+
+        // Update certified data
+        certification::update_certified_data(&state.certified_responses);
+        Ok(())
     })
 }
 
+#[query]
+fn list_fallbacks() -> Vec<(String, AssetKey)> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .fallbacks
+            .iter()
+            .map(|(prefix, key)| (prefix.clone(), key.clone()))
+            .collect()
+    })
+}
+
+/// Sets the asset served with status 404 for missing paths under `prefix`,
+/// or removes it when `key` is omitted. The prefix `/` sets the default.
+#[update]
+fn set_fallback(prefix: String, key: Option<AssetKey>) -> Result<(), Error> {
+    ensure_controller()?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.set_fallback(prefix, key)?;
+
+        // Update certified data
+        certification::update_certified_data(&state.certified_responses);
+        Ok(())
+    })
+}
+
+#[query]
+fn list_directory_listings() -> Vec<String> {
+    STATE.with(|state| state.borrow().listing_prefixes.iter().cloned().collect())
+}
+
+/// Enables or disables generated index pages for paths under `prefix`
+/// that end in `/` and have no index asset.
+#[update]
+fn set_directory_listing(prefix: String, enabled: bool) -> Result<(), Error> {
+    ensure_controller()?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.set_directory_listing(prefix, enabled)?;
+
+        // Update certified data
+        certification::update_certified_data(&state.certified_responses);
+        Ok(())
+    })
+}
+
+#[query]
+fn get_security_headers() -> SecurityHeaderPolicy {
+    STATE.with(|state| state.borrow().security_headers.default.clone())
+}
+
+#[update]
+fn set_security_headers(policy: SecurityHeaderPolicy) -> Result<(), Error> {
+    ensure_controller()?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.set_security_headers(policy)?;
+
+        // Update certified data
+        certification::update_certified_data(&state.certified_responses);
+        Ok(())
+    })
+}
+
+#[query]
+fn list_security_header_overrides() -> Vec<(String, SecurityHeaderPolicy)> {
+    STATE.with(|state| state.borrow().security_headers.list_overrides())
+}
+
+/// Overrides security headers for assets under `prefix`, or removes the
+/// override when `policy` is omitted.
+#[update]
+fn set_security_header_override(
+    prefix: String,
+    policy: Option<SecurityHeaderPolicy>,
+) -> Result<(), Error> {
+    ensure_controller()?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.set_security_header_override(prefix, policy)?;
+
+        // Update certified data
+        certification::update_certified_data(&state.certified_responses);
+        Ok(())
+    })
+}
+
+#[query]
+fn get_metrics_config() -> MetricsConfig {
+    STATE.with(|state| state.borrow().metrics.config.clone())
+}
+
+/// Sets where `/metrics` is served, if at all, and the SHA-256 of the
+/// bearer token required to read it.
+#[update]
+fn set_metrics_config(config: MetricsConfig) -> Result<(), Error> {
+    ensure_controller()?;
+    STATE.with(|state| state.borrow_mut().set_metrics_config(config))
+}
+
+/// Asset counts, storage and memory usage, cycles balance and module
+/// details for capacity planning.
+#[query]
+fn stats() -> Stats {
+    STATE.with(|state| stats::stats(&state.borrow()))
+}
+
+#[query]
+fn get_provision_canister() -> Option<Principal> {
+    STATE.with(|state| state.borrow().provision_canister)
+}
+
+#[update]
+fn set_provision_canister(canister: Option<Principal>) -> Result<(), Error> {
+    ensure_controller()?;
+    STATE.with(|state| state.borrow_mut().provision_canister = canister);
+    Ok(())
+}
+
+#[update]
+fn set_visibility(key: AssetKey, visibility: Visibility) -> Result<(), Error> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.set_visibility(&key, visibility, caller())?;
+
+        // Update certified data
+        certification::update_certified_data(&state.certified_responses);
+        Ok(())
+    })
+}
+
+#[update]
+fn grant_role(role: String, principal: Principal) -> Result<(), Error> {
+    ensure_controller()?;
+    STATE.with(|state| state.borrow_mut().grant_role(role, principal));
+    Ok(())
+}
+
+#[update]
+fn revoke_role(role: String, principal: Principal) -> Result<(), Error> {
+    ensure_controller()?;
+    STATE.with(|state| state.borrow_mut().revoke_role(&role, &principal));
+    Ok(())
+}
+
+#[query]
+fn list_role_members(role: String) -> Vec<Principal> {
+    STATE.with(|state| state.borrow().list_role_members(&role))
+}
+
+#[query]
+fn retrieve(key: AssetKey) -> Result<Vec<u8>, Error> {
+    STATE.with(|state| state.borrow().retrieve(&key, &caller()))
+}
+
 #[query]
 fn list_assets() -> Vec<AssetKey> {
-    STATE.with(|state| state.borrow().list_assets())
+    STATE.with(|state| state.borrow().list_assets(&caller()))
 }
 
 #[query]
@@ -75,6 +527,101 @@ fn http_request(req: HttpRequest) -> HttpResponse {
     STATE.with(|state| state.borrow().handle_http_request(req, &certificate))
 }
 
+#[update]
+fn http_request_update(req: HttpRequest) -> HttpResponse {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        // Uploads and generated index pages change the certified responses
+        let root_hash = state.certified_responses.root_hash();
+        let response = state.handle_http_request_update(req, &ic_cdk::id(), time());
+        if state.certified_responses.root_hash() == root_hash {
+            return response;
+        }
+
+        // Update certified data
+        certification::update_certified_data(&state.certified_responses);
+        response
+    })
+}
+
+/// Returns a URL through which the caller can fetch `key` without an
+/// authenticated agent, valid for `ttl_seconds`.
+#[update]
+async fn create_download_url(key: AssetKey, ttl_seconds: u64) -> Result<String, Error> {
+    ensure_url_signing_secret().await?;
+
+    let token = STATE.with(|state| {
+        state
+            .borrow()
+            .create_download_token(&key, caller(), ttl_seconds, time())
+    })?;
+    let urls = STATE.with(|state| state.borrow().asset_urls(&key, &ic_cdk::id()));
+    let url = urls.custom_domain.unwrap_or(urls.certified);
+    Ok(format!("{}?token={}", url, token))
+}
+
+/// Returns a token with which the caller can upload to `key` through a
+/// plain HTTP `POST` or `PUT`, sent as `Authorization: Bearer <token>`.
+#[update]
+async fn create_upload_token(key: AssetKey, ttl_seconds: u64) -> Result<String, Error> {
+    ensure_url_signing_secret().await?;
+
+    STATE.with(|state| {
+        state
+            .borrow()
+            .create_upload_token(&key, caller(), ttl_seconds, time())
+    })
+}
+
+/// Issues an API key acting on behalf of the caller. The returned key is
+/// not stored and cannot be retrieved again.
+#[update]
+async fn create_api_key(arg: CreateApiKeyArg) -> Result<String, Error> {
+    let now = time();
+    let expires_at = arg
+        .ttl_seconds
+        .map(|ttl_seconds| expiry_time(now, ttl_seconds))
+        .transpose()?;
+    let (secret,) = raw_rand()
+        .await
+        .map_err(|(_, msg)| Error::Internal(format!("Failed to generate secret: {}", msg)))?;
+    STATE.with(|state| {
+        state
+            .borrow_mut()
+            .api_keys
+            .create(caller(), arg.name, arg.scopes, expires_at, &secret, now)
+    })
+}
+
+#[query]
+fn list_api_keys() -> Vec<ApiKeyInfo> {
+    STATE.with(|state| state.borrow().api_keys.list(&caller()))
+}
+
+#[update]
+fn revoke_api_key(id: u64) -> Result<(), Error> {
+    STATE.with(|state| state.borrow_mut().api_keys.revoke(id, &caller()))
+}
+
+async fn ensure_url_signing_secret() -> Result<(), Error> {
+    if STATE.with(|state| state.borrow().url_signing_secret.is_some()) {
+        return Ok(());
+    }
+
+    let (bytes,) = raw_rand()
+        .await
+        .map_err(|(_, msg)| Error::Internal(format!("Failed to generate secret: {}", msg)))?;
+    let secret: SigningSecret = bytes.try_into().map_err(|_| {
+        Error::Internal("raw_rand returned an unexpected number of bytes".to_string())
+    })?;
+
+    // Another call may have initialized the secret while this one was waiting
+    STATE.with(|state| {
+        state.borrow_mut().url_signing_secret.get_or_insert(secret);
+    });
+    Ok(())
+}
+
 #[query]
 fn http_request_streaming_callback(
     token: types::StreamingCallbackToken,
@@ -89,7 +636,14 @@ fn http_request_streaming_callback(
     })
 }
 
-use ic_cdk_macros::query as export_query;
+fn ensure_controller() -> Result<(), Error> {
+    if !is_controller(&caller()) {
+        return Err(Error::Unauthorized(
+            "Only controllers can perform this action".to_string(),
+        ));
+    }
+    Ok(())
+}
 
 ic_cdk::export_candid!();
 
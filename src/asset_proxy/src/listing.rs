@@ -0,0 +1,120 @@
+// src/listing.rs
+//! Generated index pages for path prefixes without an index asset.
+
+use crate::types::{Asset, AssetKey, Visibility};
+use std::collections::{BTreeMap, HashMap};
+
+/// A child of a listed prefix: an asset, or a deeper prefix when `size` is
+/// `None`.
+pub struct Entry {
+    pub name: String,
+    pub size: Option<u64>,
+    pub content_type: Option<String>,
+    pub modified: Option<u64>,
+}
+
+/// The immediate children of `path`, which ends in `/`. Only public assets
+/// are listed.
+pub fn entries(assets: &HashMap<AssetKey, Asset>, path: &str) -> Vec<Entry> {
+    let mut children: BTreeMap<String, Entry> = BTreeMap::new();
+    for (key, asset) in assets {
+        if asset.visibility != Visibility::Public {
+            continue;
+        }
+        let Some(rest) = key.strip_prefix(path).filter(|rest| !rest.is_empty()) else {
+            continue;
+        };
+
+        let entry = match rest.split_once('/') {
+            Some((directory, _)) => Entry {
+                name: format!("{}/", directory),
+                size: None,
+                content_type: None,
+                modified: None,
+            },
+            None => Entry {
+                name: rest.to_string(),
+                size: asset
+                    .encodings
+                    .get("identity")
+                    .map(|encoding| encoding.total_length as u64),
+                content_type: Some(asset.content_type.clone()),
+                modified: asset
+                    .encodings
+                    .values()
+                    .map(|encoding| encoding.modified)
+                    .max(),
+            },
+        };
+        children.entry(entry.name.clone()).or_insert(entry);
+    }
+    children.into_values().collect()
+}
+
+/// Whether `path` has any children to list.
+pub fn has_entries(assets: &HashMap<AssetKey, Asset>, path: &str) -> bool {
+    assets.iter().any(|(key, asset)| {
+        asset.visibility == Visibility::Public && key.len() > path.len() && key.starts_with(path)
+    })
+}
+
+pub fn html(path: &str, entries: &[Entry]) -> String {
+    let mut rows = String::new();
+    for entry in entries {
+        let name = escape_html(&entry.name);
+        let thumbnail = match &entry.content_type {
+            Some(content_type) if content_type.starts_with("image/") => format!(
+                "<img src=\"{}\" alt=\"\" width=\"48\" height=\"48\" loading=\"lazy\">",
+                name
+            ),
+            _ => String::new(),
+        };
+        rows.push_str(&format!(
+            "<tr><td>{}</td><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            thumbnail,
+            name,
+            name,
+            entry.size.map(|size| size.to_string()).unwrap_or_default(),
+            escape_html(entry.content_type.as_deref().unwrap_or_default()),
+            entry
+                .modified
+                .map(|modified| modified.to_string())
+                .unwrap_or_default(),
+        ));
+    }
+
+    let path = escape_html(path);
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Index of {path}</title></head>\n<body><h1>Index of {path}</h1>\n<table>\n<tr><th></th><th>Name</th><th>Size</th><th>Type</th><th>Modified (ns)</th></tr>\n{rows}</table></body></html>\n",
+    )
+}
+
+pub fn json(path: &str, entries: &[Entry]) -> serde_json::Value {
+    let entries: Vec<serde_json::Value> = entries
+        .iter()
+        .map(|entry| {
+            serde_json::json!({
+                "name": entry.name,
+                "size": entry.size,
+                "content_type": entry.content_type,
+                "modified": entry.modified,
+            })
+        })
+        .collect();
+    serde_json::json!({ "path": path, "entries": entries })
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,159 @@
+// src/metrics.rs
+//! Counters and the Prometheus `/metrics` endpoint.
+//!
+//! Counters only move in update calls: state changes made while answering a
+//! query are discarded, so query traffic such as `retrieve` and certified
+//! `http_request` responses cannot be counted here.
+
+use crate::state::State;
+use crate::types::{HttpRequest, MetricsConfig};
+use crate::utils::header_value;
+use canister_error::Error;
+use ic_metrics_encoder::MetricsEncoder;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io;
+
+const WASM_PAGE_SIZE: u64 = 65536;
+
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    pub config: MetricsConfig,
+    pub store_calls: u64,
+    pub http_update_requests: u64,
+    /// Failed calls by `Error` variant.
+    pub errors: BTreeMap<&'static str, u64>,
+}
+
+impl Metrics {
+    pub fn record_error(&mut self, err: &Error) {
+        *self.errors.entry(err.kind()).or_default() += 1;
+    }
+
+    pub fn set_config(&mut self, config: MetricsConfig) -> Result<(), Error> {
+        if let Some(path) = &config.path {
+            if !path.starts_with('/') || !path.chars().all(|c| c.is_ascii_graphic()) {
+                return Err(Error::invalid_argument(
+                    "path",
+                    "must be an ASCII path starting with '/'",
+                ));
+            }
+        }
+        if config
+            .bearer_token_sha256
+            .as_ref()
+            .is_some_and(|hash| hash.len() != 32)
+        {
+            return Err(Error::invalid_argument(
+                "bearer_token_sha256",
+                "must be 32 bytes long",
+            ));
+        }
+        self.config = config;
+        Ok(())
+    }
+
+    /// Whether `path` is where metrics are served.
+    pub fn is_metrics_path(&self, path: &str) -> bool {
+        self.config.path.as_deref() == Some(path)
+    }
+
+    /// Whether the request carries the configured bearer token, if any.
+    pub fn authorizes(&self, req: &HttpRequest) -> bool {
+        let Some(expected) = &self.config.bearer_token_sha256 else {
+            return true;
+        };
+        header_value(&req.headers, "Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| {
+                Sha256::digest(token.trim().as_bytes()).as_slice() == expected.as_slice()
+            })
+    }
+}
+
+pub fn heap_memory_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+pub fn stable_memory_bytes() -> u64 {
+    ic_cdk::api::stable::stable_size() * WASM_PAGE_SIZE
+}
+
+/// Encodes the current metrics in the Prometheus text format.
+pub fn encode(state: &State, now: u64) -> io::Result<Vec<u8>> {
+    let mut w = MetricsEncoder::new(vec![], (now / 1_000_000) as i64);
+
+    w.encode_gauge(
+        "asset_proxy_assets",
+        state.assets.len() as f64,
+        "Number of stored assets.",
+    )?;
+    let mut bytes_per_encoding: BTreeMap<&str, u64> = BTreeMap::new();
+    for asset in state.assets.values() {
+        for (name, encoding) in &asset.encodings {
+            *bytes_per_encoding.entry(name).or_default() += encoding.total_length as u64;
+        }
+    }
+    let mut gauge = w.gauge_vec(
+        "asset_proxy_asset_bytes",
+        "Bytes of asset content by encoding, before deduplication.",
+    )?;
+    for (encoding, bytes) in bytes_per_encoding {
+        gauge = gauge.value(&[("encoding", encoding)], bytes as f64)?;
+    }
+    let stored_bytes: usize = state
+        .content_store
+        .blobs
+        .values()
+        .map(|blob| blob.content.len())
+        .sum();
+    w.encode_gauge(
+        "asset_proxy_stored_bytes",
+        stored_bytes as f64,
+        "Bytes held by the deduplicated content store.",
+    )?;
+
+    w.encode_gauge(
+        "asset_proxy_heap_memory_bytes",
+        heap_memory_bytes() as f64,
+        "Size of the canister heap.",
+    )?;
+    w.encode_gauge(
+        "asset_proxy_stable_memory_bytes",
+        stable_memory_bytes() as f64,
+        "Size of the canister's stable memory.",
+    )?;
+    w.encode_gauge(
+        "asset_proxy_cycles_balance",
+        ic_cdk::api::canister_balance128() as f64,
+        "Cycles balance of the canister.",
+    )?;
+
+    let metrics = &state.metrics;
+    w.encode_counter(
+        "asset_proxy_store_calls_total",
+        metrics.store_calls as f64,
+        "Calls to store, including HTTP uploads.",
+    )?;
+    w.encode_counter(
+        "asset_proxy_http_update_requests_total",
+        metrics.http_update_requests as f64,
+        "HTTP requests handled in update calls.",
+    )?;
+    let mut counter = w.counter_vec(
+        "asset_proxy_errors_total",
+        "Failed stores and HTTP update requests by error type.",
+    )?;
+    for (kind, count) in &metrics.errors {
+        counter = counter.value(&[("type", kind)], *count as f64)?;
+    }
+
+    Ok(w.into_inner())
+}
@@ -0,0 +1,132 @@
+// src/multipart.rs
+//! Minimal `multipart/form-data` parsing for HTTP uploads.
+
+use canister_error::Error;
+
+/// One part of a form, borrowing its content from the request body.
+pub struct Part<'a> {
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub content: &'a [u8],
+}
+
+/// The `boundary` parameter of a `multipart/form-data` content type, or
+/// `None` for any other content type.
+pub fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    let media_type = params.next()?.trim();
+    if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim_matches('"').to_string())
+}
+
+pub fn parse<'a>(body: &'a [u8], boundary: &str) -> Result<Vec<Part<'a>>, Error> {
+    let malformed = |reason: &str| Error::invalid_argument("body", reason);
+    let delimiter = format!("--{}", boundary);
+    let part_end = format!("\r\n--{}", boundary);
+
+    let start = find(body, delimiter.as_bytes()).ok_or_else(|| malformed("missing boundary"))?;
+    let mut rest = &body[start + delimiter.len()..];
+    let mut parts = vec![];
+    loop {
+        // The last delimiter is followed by `--`
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        rest = rest
+            .strip_prefix(b"\r\n")
+            .ok_or_else(|| malformed("expected a line break after the boundary"))?;
+
+        let headers_end =
+            find(rest, b"\r\n\r\n").ok_or_else(|| malformed("unterminated part headers"))?;
+        let headers = std::str::from_utf8(&rest[..headers_end])
+            .map_err(|_| malformed("part headers are not UTF-8"))?;
+        rest = &rest[headers_end + 4..];
+
+        let content_end =
+            find(rest, part_end.as_bytes()).ok_or_else(|| malformed("unterminated part"))?;
+        let mut part = Part {
+            name: None,
+            filename: None,
+            content_type: None,
+            content: &rest[..content_end],
+        };
+        for line in headers.split("\r\n") {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            if name.trim().eq_ignore_ascii_case("Content-Disposition") {
+                part.name = disposition_param(value, "name");
+                part.filename = disposition_param(value, "filename");
+            } else if name.trim().eq_ignore_ascii_case("Content-Type") {
+                part.content_type = Some(value.trim().to_string());
+            }
+        }
+        parts.push(part);
+        rest = &rest[content_end + part_end.len()..];
+    }
+}
+
+fn disposition_param(value: &str, name: &str) -> Option<String> {
+    value
+        .split(';')
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(param, _)| param.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim_matches('"').to_string())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_boundary() {
+        assert_eq!(
+            boundary("multipart/form-data; boundary=\"abc\"").as_deref(),
+            Some("abc")
+        );
+        assert_eq!(boundary("text/plain; boundary=abc"), None);
+    }
+
+    #[test]
+    fn parses_every_part() {
+        let body = b"preamble\r\n--xyz\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+            Hello\r\n--xyz\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"a.png\"\r\n\
+            Content-Type: image/png\r\n\r\n\
+            \x89PNG\r\n\r\n--xyz--\r\n";
+        let parts = parse(body, "xyz").unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name.as_deref(), Some("title"));
+        assert_eq!(parts[0].filename, None);
+        assert_eq!(parts[0].content, b"Hello");
+        assert_eq!(parts[1].filename.as_deref(), Some("a.png"));
+        assert_eq!(parts[1].content_type.as_deref(), Some("image/png"));
+        assert_eq!(parts[1].content, b"\x89PNG\r\n");
+    }
+
+    #[test]
+    fn rejects_malformed_bodies() {
+        let bodies: [&[u8]; 4] = [
+            b"no boundary at all",
+            b"--xyz no line break",
+            b"--xyz\r\nContent-Disposition: form-data\r\n",
+            b"--xyz\r\nContent-Disposition: form-data\r\n\r\nnever closed",
+        ];
+        for body in bodies {
+            assert!(parse(body, "xyz").is_err());
+        }
+    }
+}
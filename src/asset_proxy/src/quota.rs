@@ -0,0 +1,167 @@
+// src/quota.rs
+//! Per-principal storage accounting and quota enforcement.
+
+use crate::types::{AssetKey, QuotaConfig, Usage};
+use candid::Principal;
+use canister_error::Error;
+use std::collections::HashMap;
+
+/// Tracks how much storage each owner holds, updated on every store and
+/// delete so quota checks never have to scan all assets.
+#[derive(Clone, Debug, Default)]
+pub struct QuotaTracker {
+    pub config: QuotaConfig,
+    pub usage: HashMap<Principal, Usage>,
+}
+
+impl QuotaTracker {
+    pub fn usage_of(&self, principal: &Principal) -> Usage {
+        self.usage.get(principal).cloned().unwrap_or_default()
+    }
+
+    /// Checks whether `owner` may store `upload_size` bytes under `key`,
+    /// leaving the asset `asset_size` bytes large in total. `previous_size`
+    /// is the size of the asset being replaced, if it already exists.
+    /// Version history does not count here: it is pruned to make room.
+    pub fn check(
+        &self,
+        owner: &Principal,
+        key: &AssetKey,
+        upload_size: u64,
+        asset_size: u64,
+        previous_size: Option<u64>,
+    ) -> Result<(), Error> {
+        if let Some(max) = self.config.max_asset_size {
+            if upload_size > max {
+                return Err(Error::QuotaExceeded(format!(
+                    "Asset size of {} bytes exceeds the maximum of {} bytes",
+                    upload_size, max
+                )));
+            }
+        }
+
+        let usage = self.usage_of(owner);
+        // An existing asset is replaced rather than added
+        let (held_bytes, held_count) = match previous_size {
+            Some(size) => (size, 1),
+            None => (0, 0),
+        };
+
+        if let Some(max) = self.config.max_total_bytes {
+            let current_bytes = usage.total_bytes.saturating_sub(usage.history_bytes);
+            let total = current_bytes.saturating_sub(held_bytes) + asset_size;
+            if total > max {
+                return Err(Error::QuotaExceeded(format!(
+                    "Storing {} would use {} bytes, exceeding the quota of {} bytes ({} bytes in use)",
+                    key, total, max, current_bytes
+                )));
+            }
+        }
+
+        if let Some(max) = self.config.max_asset_count {
+            let count = usage.asset_count.saturating_sub(held_count) + 1;
+            if count > max {
+                return Err(Error::QuotaExceeded(format!(
+                    "Storing {} would exceed the quota of {} assets",
+                    key, max
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Charges an asset of `size` bytes to `owner`, replacing the charge for
+    /// its previous version if there was one.
+    pub fn charge(&mut self, owner: Principal, previous_size: Option<u64>, size: u64) {
+        let usage = self.usage.entry(owner).or_default();
+        match previous_size {
+            Some(previous_size) => {
+                usage.total_bytes = usage.total_bytes.saturating_sub(previous_size);
+            }
+            None => usage.asset_count += 1,
+        }
+        usage.total_bytes += size;
+    }
+
+    /// Moves the charge for version history `owner` keeps of one key from
+    /// `before` to `after` bytes.
+    pub fn charge_history(&mut self, owner: Principal, before: u64, after: u64) {
+        if before == after {
+            return;
+        }
+        let usage = self.usage.entry(owner).or_default();
+        usage.history_bytes = usage.history_bytes.saturating_sub(before) + after;
+        usage.total_bytes = usage.total_bytes.saturating_sub(before) + after;
+    }
+
+    /// Whether `owner` holds more bytes, history included, than the quota
+    /// allows.
+    pub fn over_total_bytes(&self, owner: &Principal) -> bool {
+        self.config
+            .max_total_bytes
+            .is_some_and(|max| self.usage_of(owner).total_bytes > max)
+    }
+
+    /// Removes the charge for an asset of `size` bytes from `owner`.
+    pub fn release(&mut self, owner: &Principal, size: u64) {
+        if let Some(usage) = self.usage.get_mut(owner) {
+            usage.total_bytes = usage.total_bytes.saturating_sub(size);
+            usage.asset_count = usage.asset_count.saturating_sub(1);
+            if usage.asset_count == 0 {
+                self.usage.remove(owner);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker() -> QuotaTracker {
+        QuotaTracker {
+            config: QuotaConfig {
+                max_asset_size: Some(8),
+                max_total_bytes: Some(10),
+                max_asset_count: Some(2),
+            },
+            usage: HashMap::new(),
+        }
+    }
+
+    fn owner() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    #[test]
+    fn overwrites_replace_the_previous_charge() {
+        let mut quotas = tracker();
+        let key = "/a.txt".to_string();
+        quotas.charge(owner(), None, 6);
+
+        // A new key adds to the 6 bytes held, an overwrite replaces them
+        assert!(quotas.check(&owner(), &key, 6, 6, None).is_err());
+        assert!(quotas.check(&owner(), &key, 8, 10, Some(6)).is_ok());
+        assert!(quotas.check(&owner(), &key, 9, 9, Some(6)).is_err());
+
+        quotas.charge(owner(), Some(6), 10);
+        let usage = quotas.usage_of(&owner());
+        assert_eq!((usage.total_bytes, usage.asset_count), (10, 1));
+    }
+
+    #[test]
+    fn asset_count_only_grows_with_new_keys() {
+        let mut quotas = tracker();
+        let key = "/c.txt".to_string();
+        quotas.charge(owner(), None, 1);
+        quotas.charge(owner(), None, 1);
+
+        assert!(quotas.check(&owner(), &key, 1, 1, None).is_err());
+        assert!(quotas.check(&owner(), &key, 1, 1, Some(1)).is_ok());
+
+        quotas.release(&owner(), 1);
+        quotas.release(&owner(), 1);
+        assert!(quotas.usage.is_empty());
+    }
+}
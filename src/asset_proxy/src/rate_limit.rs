@@ -0,0 +1,139 @@
+// src/rate_limit.rs
+//! Token-bucket throttling of uploads per caller.
+
+use crate::types::RateLimitConfig;
+use candid::Principal;
+use canister_error::Error;
+use std::collections::HashMap;
+
+const NANOS_PER_MINUTE: f64 = 60_000_000_000.0;
+
+/// Buckets are dropped once they refill, but only when this many have
+/// accumulated, to keep the cleanup off the hot path.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Clone, Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: u64,
+}
+
+impl Bucket {
+    fn refilled(&self, config: &RateLimitConfig, now: u64) -> f64 {
+        let elapsed = now.saturating_sub(self.last_refill) as f64;
+        let refill = elapsed * config.refill_per_minute as f64 / NANOS_PER_MINUTE;
+        (self.tokens + refill).min(config.capacity as f64)
+    }
+}
+
+/// Per-caller token buckets. Every bucket starts full and refills
+/// continuously at the configured rate; each upload takes one token.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimiter {
+    /// `None` disables rate limiting altogether.
+    pub config: Option<RateLimitConfig>,
+    buckets: HashMap<Principal, Bucket>,
+}
+
+impl RateLimiter {
+    /// Tokens the caller would have available at `now`.
+    fn available(&self, config: &RateLimitConfig, caller: &Principal, now: u64) -> f64 {
+        match self.buckets.get(caller) {
+            Some(bucket) => bucket.refilled(config, now),
+            None => config.capacity as f64,
+        }
+    }
+
+    /// Whether the caller has a token left, without taking it.
+    pub fn allows(&self, caller: &Principal, now: u64) -> bool {
+        match &self.config {
+            Some(config) => self.available(config, caller, now) >= 1.0,
+            None => true,
+        }
+    }
+
+    /// Takes a token from the caller's bucket, failing if it is empty.
+    pub fn acquire(&mut self, caller: Principal, now: u64) -> Result<(), Error> {
+        let Some(config) = self.config.clone() else {
+            return Ok(());
+        };
+
+        let tokens = self.available(&config, &caller, now);
+        if tokens < 1.0 {
+            return Err(Error::RateLimited(format!(
+                "Rate limit exceeded: at most {} uploads per minute (burst of {})",
+                config.refill_per_minute, config.capacity
+            )));
+        }
+
+        if self.buckets.len() >= PRUNE_THRESHOLD {
+            let capacity = config.capacity as f64;
+            self.buckets
+                .retain(|_, bucket| bucket.refilled(&config, now) < capacity);
+        }
+
+        self.buckets.insert(
+            caller,
+            Bucket {
+                tokens: tokens - 1.0,
+                last_refill: now,
+            },
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter {
+            config: Some(RateLimitConfig {
+                capacity: 2,
+                refill_per_minute: 60,
+            }),
+            buckets: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn buckets_empty_after_their_capacity() {
+        let mut limiter = limiter();
+        let caller = Principal::anonymous();
+        limiter.acquire(caller, 0).unwrap();
+        limiter.acquire(caller, 0).unwrap();
+        assert!(!limiter.allows(&caller, 0));
+        assert!(limiter.acquire(caller, 0).is_err());
+
+        // Other callers have buckets of their own
+        assert!(limiter.acquire(Principal::from_slice(&[1]), 0).is_ok());
+    }
+
+    #[test]
+    fn buckets_refill_up_to_their_capacity() {
+        let mut limiter = limiter();
+        let caller = Principal::anonymous();
+        limiter.acquire(caller, 0).unwrap();
+        limiter.acquire(caller, 0).unwrap();
+
+        assert!(!limiter.allows(&caller, SECOND / 2));
+        limiter.acquire(caller, SECOND).unwrap();
+        assert!(limiter.acquire(caller, SECOND).is_err());
+
+        // A long pause refills no more than the capacity
+        limiter.acquire(caller, 60 * SECOND).unwrap();
+        limiter.acquire(caller, 60 * SECOND).unwrap();
+        assert!(limiter.acquire(caller, 60 * SECOND).is_err());
+    }
+
+    #[test]
+    fn no_config_allows_everything() {
+        let mut limiter = RateLimiter::default();
+        for _ in 0..100 {
+            limiter.acquire(Principal::anonymous(), 0).unwrap();
+        }
+    }
+}
@@ -5,7 +5,7 @@ use candid::{
     types::{Serializer as CandidSerializer, Type, TypeInner},
     CandidType, Deserialize as CandidDeserialize,
 };
-use serde::{Deserialize, Deserializer, Serialize, Serializer as SerdeSerializer};
+use serde::{Deserializer, Serialize, Serializer as SerdeSerializer};
 use serde_bytes::ByteBuf;
 use std::convert::AsRef;
 use std::ops::Deref;
@@ -0,0 +1,310 @@
+// src/routing.rs
+//! Redirect and rewrite rules, evaluated before asset lookup.
+//!
+//! A rule source is either an exact path such as `/old.png` or a prefix
+//! ending in `*` such as `/cars/*`. Prefixes match whole segments, so
+//! `/cars*` covers `/cars/a.png` but not `/carsharing`. A destination may end
+//! in `*` too, which is replaced by the part of the path the source's `*`
+//! matched.
+
+use crate::types::{RoutingRule, RuleAction};
+use crate::utils::is_under;
+use canister_error::Error;
+
+/// Most rules the table may hold.
+const MAX_RULES: usize = 1000;
+
+/// What to do with a request a rule matched.
+pub enum Route {
+    Redirect {
+        status_code: u16,
+        location: String,
+    },
+    /// Serve the asset at this path instead.
+    Rewrite(String),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RoutingRules {
+    rules: Vec<RoutingRule>,
+}
+
+impl RoutingRules {
+    pub fn list(&self) -> Vec<RoutingRule> {
+        self.rules.clone()
+    }
+
+    pub fn set(&mut self, rules: Vec<RoutingRule>) -> Result<(), Error> {
+        if rules.len() > MAX_RULES {
+            return Err(Error::invalid_argument(
+                "rules",
+                format!("at most {} rules are allowed", MAX_RULES),
+            ));
+        }
+        for (index, rule) in rules.iter().enumerate() {
+            validate_rule(rule)?;
+            if rules[..index]
+                .iter()
+                .any(|other| other.source == rule.source)
+            {
+                return Err(Error::invalid_argument(
+                    "source",
+                    format!("{:?} appears more than once", rule.source),
+                ));
+            }
+        }
+        self.rules = rules;
+        Ok(())
+    }
+
+    /// Sources of exact rules, each of which is certified as a path of its own.
+    pub fn exact_sources(&self) -> impl Iterator<Item = &String> {
+        self.rules
+            .iter()
+            .filter(|rule| !is_prefix(&rule.source))
+            .map(|rule| &rule.source)
+    }
+
+    /// The prefixes of prefix rules, without their `*`.
+    pub fn prefixes(&self) -> impl Iterator<Item = &str> {
+        self.rules
+            .iter()
+            .filter_map(|rule| rule.source.strip_suffix('*'))
+    }
+
+    /// Whether some rule's destination is `path`, so the responses of its
+    /// sources change along with the asset there.
+    pub fn targets(&self, path: &str) -> bool {
+        self.rules.iter().any(|rule| rule.destination == path)
+    }
+
+    pub fn is_exact_source(&self, path: &str) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| !is_prefix(&rule.source) && rule.source == path)
+    }
+
+    /// The route for `path`: an exact rule wins over prefixes, and a longer
+    /// prefix over a shorter one.
+    pub fn resolve(&self, path: &str) -> Option<Route> {
+        match self.rules.iter().find(|rule| rule.source == path) {
+            Some(rule) => Some(route(rule, "")),
+            None => self.prefix_rule(path).map(|(rule, rest)| route(rule, rest)),
+        }
+    }
+
+    /// The route shared by every path under `prefix` that no exact rule or
+    /// longer prefix matches: `Some(None)` if no rule applies to them, and
+    /// `None` if the route differs between them because the destination
+    /// ends in `*`.
+    pub fn route_below(&self, prefix: &str) -> Option<Option<Route>> {
+        match self.prefix_rule(prefix) {
+            Some((rule, _)) if is_prefix(&rule.destination) => None,
+            Some((rule, _)) => Some(Some(route(rule, ""))),
+            None => Some(None),
+        }
+    }
+
+    /// Whether `path` is routed by a prefix rule whose destination ends in
+    /// `*`, so no other path shares its route.
+    pub fn routes_by_suffix(&self, path: &str) -> bool {
+        !self.rules.iter().any(|rule| rule.source == path)
+            && self
+                .prefix_rule(path)
+                .is_some_and(|(rule, _)| is_prefix(&rule.destination))
+    }
+
+    /// The longest prefix rule covering `path`, with the part of the path
+    /// its `*` matched.
+    fn prefix_rule<'a>(&self, path: &'a str) -> Option<(&RoutingRule, &'a str)> {
+        self.rules
+            .iter()
+            .filter_map(|rule| {
+                let prefix = rule.source.strip_suffix('*')?;
+                is_under(path, prefix).then(|| (rule, &path[prefix.len()..]))
+            })
+            .max_by_key(|(rule, _)| rule.source.len())
+    }
+}
+
+fn route(rule: &RoutingRule, rest: &str) -> Route {
+    let target = match rule.destination.strip_suffix('*') {
+        Some(base) => format!("{}{}", base, rest),
+        None => rule.destination.clone(),
+    };
+    match rule.action {
+        RuleAction::Redirect { status_code } => Route::Redirect {
+            status_code,
+            location: target,
+        },
+        RuleAction::Rewrite => Route::Rewrite(target),
+    }
+}
+
+fn is_prefix(source: &str) -> bool {
+    source.ends_with('*')
+}
+
+fn validate_rule(rule: &RoutingRule) -> Result<(), Error> {
+    if !rule.source.starts_with('/') {
+        return Err(Error::invalid_argument("source", "must start with '/'"));
+    }
+    if rule.source.trim_end_matches('*').contains('*') || rule.source.ends_with("**") {
+        return Err(Error::invalid_argument(
+            "source",
+            "'*' is only allowed at the end",
+        ));
+    }
+    if rule.destination.trim_end_matches('*').contains('*') || rule.destination.ends_with("**") {
+        return Err(Error::invalid_argument(
+            "destination",
+            "'*' is only allowed at the end",
+        ));
+    }
+    if is_prefix(&rule.destination) && !is_prefix(&rule.source) {
+        return Err(Error::invalid_argument(
+            "destination",
+            "may only end in '*' if the source does",
+        ));
+    }
+
+    match rule.action {
+        RuleAction::Redirect { status_code } => {
+            if ![301, 302, 307, 308].contains(&status_code) {
+                return Err(Error::invalid_argument(
+                    "status_code",
+                    "must be 301, 302, 307 or 308",
+                ));
+            }
+            let is_url =
+                rule.destination.starts_with("https://") || rule.destination.starts_with("http://");
+            if !is_url && !rule.destination.starts_with('/') {
+                return Err(Error::invalid_argument(
+                    "destination",
+                    "must be a path or an http(s) URL",
+                ));
+            }
+        }
+        RuleAction::Rewrite => {
+            if !rule.destination.starts_with('/') {
+                return Err(Error::invalid_argument(
+                    "destination",
+                    "rewrites must point to a path",
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(source: &str, destination: &str, action: RuleAction) -> RoutingRule {
+        RoutingRule {
+            source: source.to_string(),
+            destination: destination.to_string(),
+            action,
+        }
+    }
+
+    fn rules(rules: Vec<RoutingRule>) -> RoutingRules {
+        let mut routing = RoutingRules::default();
+        routing.set(rules).unwrap();
+        routing
+    }
+
+    fn target(routing: &RoutingRules, path: &str) -> Option<String> {
+        routing.resolve(path).map(|route| match route {
+            Route::Redirect { location, .. } => location,
+            Route::Rewrite(target) => target,
+        })
+    }
+
+    #[test]
+    fn only_star_destinations_route_by_suffix() {
+        let routing = rules(vec![
+            rule("/docs/*", "/manual/*", RuleAction::Rewrite),
+            rule("/docs/old.html", "/manual/new.html", RuleAction::Rewrite),
+            rule("/blog/*", "/", RuleAction::Redirect { status_code: 301 }),
+        ]);
+        assert!(routing.routes_by_suffix("/docs/intro.html"));
+        assert!(!routing.routes_by_suffix("/docs/old.html"));
+        assert!(!routing.routes_by_suffix("/blog/post"));
+        assert!(!routing.routes_by_suffix("/other"));
+    }
+
+    #[test]
+    fn exact_rules_win_over_prefixes() {
+        let routing = rules(vec![
+            rule("/docs/*", "/manual/*", RuleAction::Rewrite),
+            rule("/docs/old.html", "/manual/new.html", RuleAction::Rewrite),
+        ]);
+        assert_eq!(
+            target(&routing, "/docs/old.html").as_deref(),
+            Some("/manual/new.html")
+        );
+        assert_eq!(
+            target(&routing, "/docs/intro.html").as_deref(),
+            Some("/manual/intro.html")
+        );
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let routing = rules(vec![
+            rule("/*", "/index.html", RuleAction::Rewrite),
+            rule(
+                "/cars/*",
+                "https://cars.example.com/*",
+                RuleAction::Redirect { status_code: 308 },
+            ),
+            rule("/cars/old/*", "/archive/*", RuleAction::Rewrite),
+        ]);
+        assert_eq!(
+            target(&routing, "/cars/old/a.png").as_deref(),
+            Some("/archive/a.png")
+        );
+        assert!(matches!(
+            routing.resolve("/cars/new/a.png"),
+            Some(Route::Redirect { status_code: 308, location }) if location == "https://cars.example.com/new/a.png"
+        ));
+        assert_eq!(target(&routing, "/boats").as_deref(), Some("/index.html"));
+    }
+
+    #[test]
+    fn prefixes_match_whole_segments() {
+        let routing = rules(vec![rule("/cars*", "/vehicles*", RuleAction::Rewrite)]);
+        assert_eq!(target(&routing, "/cars").as_deref(), Some("/vehicles"));
+        assert_eq!(
+            target(&routing, "/cars/a.png").as_deref(),
+            Some("/vehicles/a.png")
+        );
+        assert_eq!(target(&routing, "/carsharing"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let mut routing = RoutingRules::default();
+        assert!(routing
+            .set(vec![rule("/a", "/b*", RuleAction::Rewrite)])
+            .is_err());
+        assert!(routing
+            .set(vec![rule("/a*b", "/b", RuleAction::Rewrite)])
+            .is_err());
+        assert!(routing
+            .set(vec![rule(
+                "/a",
+                "/b",
+                RuleAction::Redirect { status_code: 200 }
+            )])
+            .is_err());
+        assert!(routing
+            .set(vec![
+                rule("/a", "/b", RuleAction::Rewrite),
+                rule("/a", "/c", RuleAction::Rewrite),
+            ])
+            .is_err());
+    }
+}
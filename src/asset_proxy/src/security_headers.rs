@@ -0,0 +1,195 @@
+// src/security_headers.rs
+//! Security headers sent with served assets, with per-prefix overrides.
+
+use crate::types::SecurityHeaderPolicy;
+use crate::utils::is_under;
+use canister_error::Error;
+use std::collections::BTreeMap;
+
+/// The default policy and the overrides layered on top of it.
+#[derive(Clone, Debug)]
+pub struct SecurityHeaders {
+    pub default: SecurityHeaderPolicy,
+    overrides: BTreeMap<String, SecurityHeaderPolicy>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        SecurityHeaders {
+            default: SecurityHeaderPolicy {
+                strict_transport_security: Some("max-age=31536000; includeSubDomains".to_string()),
+                content_security_policy: None,
+                frame_ancestors: Some("'none'".to_string()),
+                referrer_policy: Some("strict-origin-when-cross-origin".to_string()),
+                permissions_policy: None,
+                cross_origin_resource_policy: None,
+                cross_origin_embedder_policy: None,
+            },
+            overrides: BTreeMap::new(),
+        }
+    }
+}
+
+impl SecurityHeaders {
+    pub fn set_default(&mut self, policy: SecurityHeaderPolicy) -> Result<(), Error> {
+        validate_policy(&policy)?;
+        self.default = policy;
+        Ok(())
+    }
+
+    /// Sets the override for `prefix`, or removes it when `policy` is `None`.
+    pub fn set_override(
+        &mut self,
+        prefix: String,
+        policy: Option<SecurityHeaderPolicy>,
+    ) -> Result<(), Error> {
+        if !prefix.starts_with('/') {
+            return Err(Error::invalid_argument("prefix", "must start with '/'"));
+        }
+        match policy {
+            Some(policy) => {
+                validate_policy(&policy)?;
+                self.overrides.insert(prefix, policy);
+            }
+            None => {
+                self.overrides.remove(&prefix);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn list_overrides(&self) -> Vec<(String, SecurityHeaderPolicy)> {
+        self.overrides
+            .iter()
+            .map(|(prefix, policy)| (prefix.clone(), policy.clone()))
+            .collect()
+    }
+
+    pub fn override_prefixes(&self) -> impl Iterator<Item = &String> {
+        self.overrides.keys()
+    }
+
+    /// Headers for `path`: the default policy with every matching override
+    /// applied, shortest prefix first.
+    pub fn headers_for(&self, path: &str) -> Vec<(String, String)> {
+        let mut policy = self.default.clone();
+        for (prefix, overrides) in &self.overrides {
+            if is_under(path, prefix) {
+                apply(&mut policy, overrides);
+            }
+        }
+        headers(&policy)
+    }
+}
+
+/// Fields set in `overrides` replace those of `policy`; an empty string
+/// removes the header.
+fn apply(policy: &mut SecurityHeaderPolicy, overrides: &SecurityHeaderPolicy) {
+    let fields = [
+        (
+            &mut policy.strict_transport_security,
+            &overrides.strict_transport_security,
+        ),
+        (
+            &mut policy.content_security_policy,
+            &overrides.content_security_policy,
+        ),
+        (&mut policy.frame_ancestors, &overrides.frame_ancestors),
+        (&mut policy.referrer_policy, &overrides.referrer_policy),
+        (
+            &mut policy.permissions_policy,
+            &overrides.permissions_policy,
+        ),
+        (
+            &mut policy.cross_origin_resource_policy,
+            &overrides.cross_origin_resource_policy,
+        ),
+        (
+            &mut policy.cross_origin_embedder_policy,
+            &overrides.cross_origin_embedder_policy,
+        ),
+    ];
+    for (field, value) in fields {
+        if let Some(value) = value {
+            *field = Some(value.clone());
+        }
+    }
+}
+
+fn headers(policy: &SecurityHeaderPolicy) -> Vec<(String, String)> {
+    let set = |value: &Option<String>| value.clone().filter(|value| !value.is_empty());
+    let mut headers = vec![];
+    if let Some(value) = set(&policy.strict_transport_security) {
+        headers.push(("Strict-Transport-Security".to_string(), value));
+    }
+
+    // frame-ancestors is a CSP directive; X-Frame-Options covers older browsers
+    let frame_ancestors = set(&policy.frame_ancestors);
+    let csp = match (set(&policy.content_security_policy), &frame_ancestors) {
+        (Some(csp), Some(ancestors)) => Some(format!("{}; frame-ancestors {}", csp, ancestors)),
+        (Some(csp), None) => Some(csp),
+        (None, Some(ancestors)) => Some(format!("frame-ancestors {}", ancestors)),
+        (None, None) => None,
+    };
+    if let Some(csp) = csp {
+        headers.push(("Content-Security-Policy".to_string(), csp));
+    }
+    match frame_ancestors.as_deref() {
+        Some("'none'") => headers.push(("X-Frame-Options".to_string(), "DENY".to_string())),
+        Some("'self'") => headers.push(("X-Frame-Options".to_string(), "SAMEORIGIN".to_string())),
+        _ => {}
+    }
+
+    let named = [
+        ("Referrer-Policy", &policy.referrer_policy),
+        ("Permissions-Policy", &policy.permissions_policy),
+        (
+            "Cross-Origin-Resource-Policy",
+            &policy.cross_origin_resource_policy,
+        ),
+        (
+            "Cross-Origin-Embedder-Policy",
+            &policy.cross_origin_embedder_policy,
+        ),
+    ];
+    for (name, value) in named {
+        if let Some(value) = set(value) {
+            headers.push((name.to_string(), value));
+        }
+    }
+    headers
+}
+
+/// Header values may not contain line breaks or other control characters.
+fn validate_policy(policy: &SecurityHeaderPolicy) -> Result<(), Error> {
+    let fields = [
+        (
+            "strict_transport_security",
+            &policy.strict_transport_security,
+        ),
+        ("content_security_policy", &policy.content_security_policy),
+        ("frame_ancestors", &policy.frame_ancestors),
+        ("referrer_policy", &policy.referrer_policy),
+        ("permissions_policy", &policy.permissions_policy),
+        (
+            "cross_origin_resource_policy",
+            &policy.cross_origin_resource_policy,
+        ),
+        (
+            "cross_origin_embedder_policy",
+            &policy.cross_origin_embedder_policy,
+        ),
+    ];
+    for (field, value) in fields {
+        if value
+            .as_deref()
+            .is_some_and(|value| value.chars().any(|c| c.is_control()))
+        {
+            return Err(Error::invalid_argument(
+                field,
+                "must not contain control characters",
+            ));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,161 @@
+// src/signed_url.rs
+//! Time-limited tokens for downloading assets that are not publicly served
+//! and for uploading over plain HTTP.
+//!
+//! A token is `expires_at || principal || HMAC-SHA256(secret, purpose || key || expires_at || principal)`
+//! encoded as URL-safe base64, so it can be passed in a plain `?token=` query parameter.
+
+use base64::prelude::*;
+use candid::Principal;
+use canister_error::Error;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub type SigningSecret = [u8; 32];
+
+const MAC_LENGTH: usize = 32;
+
+/// What a token may be used for; a download token cannot be used to upload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Purpose {
+    Download,
+    Upload,
+}
+
+/// What a valid token grants: access to one key on behalf of `principal`.
+pub struct SignedToken {
+    pub expires_at: u64,
+    pub principal: Principal,
+}
+
+fn mac(
+    secret: &SigningSecret,
+    purpose: Purpose,
+    key: &str,
+    expires_at: u64,
+    principal: &Principal,
+) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&[purpose as u8]);
+    mac.update(&(key.len() as u64).to_be_bytes());
+    mac.update(key.as_bytes());
+    mac.update(&expires_at.to_be_bytes());
+    mac.update(principal.as_slice());
+    mac
+}
+
+/// Mints a token granting `token.principal` access to `key` until `token.expires_at`.
+pub fn sign(secret: &SigningSecret, purpose: Purpose, key: &str, token: &SignedToken) -> String {
+    let principal = token.principal.as_slice();
+    let tag = mac(secret, purpose, key, token.expires_at, &token.principal)
+        .finalize()
+        .into_bytes();
+
+    let mut bytes = Vec::with_capacity(9 + principal.len() + MAC_LENGTH);
+    bytes.extend_from_slice(&token.expires_at.to_be_bytes());
+    bytes.push(principal.len() as u8);
+    bytes.extend_from_slice(principal);
+    bytes.extend_from_slice(&tag);
+    BASE64_URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Checks that `encoded` was minted for `purpose` on `key` and has not
+/// expired at `now`.
+pub fn verify(
+    secret: &SigningSecret,
+    purpose: Purpose,
+    key: &str,
+    encoded: &str,
+    now: u64,
+) -> Result<SignedToken, Error> {
+    let malformed = || Error::invalid_argument("token", "malformed token");
+    let bytes = BASE64_URL_SAFE_NO_PAD
+        .decode(encoded)
+        .map_err(|_| malformed())?;
+
+    let (expires_at, rest) = bytes.split_at_checked(8).ok_or_else(malformed)?;
+    let expires_at = u64::from_be_bytes(expires_at.try_into().map_err(|_| malformed())?);
+    let (&principal_length, rest) = rest.split_first().ok_or_else(malformed)?;
+    let (principal, tag) = rest
+        .split_at_checked(principal_length as usize)
+        .ok_or_else(malformed)?;
+    if tag.len() != MAC_LENGTH {
+        return Err(malformed());
+    }
+    let principal = Principal::try_from_slice(principal).map_err(|_| malformed())?;
+
+    mac(secret, purpose, key, expires_at, &principal)
+        .verify(tag)
+        .map_err(|_| Error::Unauthorized("Invalid token".to_string()))?;
+    if now >= expires_at {
+        return Err(Error::Unauthorized("Token has expired".to_string()));
+    }
+
+    Ok(SignedToken {
+        expires_at,
+        principal,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: SigningSecret = [7; 32];
+    const KEY: &str = "/private/report.pdf";
+
+    fn token(expires_at: u64) -> String {
+        let token = SignedToken {
+            expires_at,
+            principal: Principal::from_slice(&[1, 2, 3]),
+        };
+        sign(&SECRET, Purpose::Download, KEY, &token)
+    }
+
+    #[test]
+    fn round_trip() {
+        let verified = verify(&SECRET, Purpose::Download, KEY, &token(100), 99).unwrap();
+        assert_eq!(verified.expires_at, 100);
+        assert_eq!(verified.principal, Principal::from_slice(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn rejects_other_key_purpose_or_secret() {
+        let token = token(100);
+        assert!(verify(&SECRET, Purpose::Download, "/private/other.pdf", &token, 0).is_err());
+        assert!(verify(&SECRET, Purpose::Upload, KEY, &token, 0).is_err());
+        assert!(verify(&[8; 32], Purpose::Download, KEY, &token, 0).is_err());
+    }
+
+    #[test]
+    fn rejects_tampered_tokens() {
+        let mut bytes = BASE64_URL_SAFE_NO_PAD.decode(token(100)).unwrap();
+
+        // Pushing the expiry back invalidates the MAC
+        bytes[7] = 200;
+        let extended = BASE64_URL_SAFE_NO_PAD.encode(&bytes);
+        assert!(matches!(
+            verify(&SECRET, Purpose::Download, KEY, &extended, 0),
+            Err(Error::Unauthorized(_))
+        ));
+
+        bytes.pop();
+        let truncated = BASE64_URL_SAFE_NO_PAD.encode(&bytes);
+        assert!(matches!(
+            verify(&SECRET, Purpose::Download, KEY, &truncated, 0),
+            Err(Error::InvalidArgument { .. })
+        ));
+        assert!(verify(&SECRET, Purpose::Download, KEY, "not a token!", 0).is_err());
+    }
+
+    #[test]
+    fn expires() {
+        assert!(verify(&SECRET, Purpose::Download, KEY, &token(100), 99).is_ok());
+        assert!(matches!(
+            verify(&SECRET, Purpose::Download, KEY, &token(100), 100),
+            Err(Error::Unauthorized(_))
+        ));
+    }
+}
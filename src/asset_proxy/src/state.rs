@@ -1,34 +1,164 @@
 // src/state.rs
-use crate::certification::{create_asset_witness, verify_asset_integrity};
-use crate::certification::{on_asset_change, AssetHashes};
-use crate::types::{Asset, AssetEncoding, HttpResponse, StreamingCallbackToken, StreamingStrategy};
+use crate::api_keys::{Access, ApiKeys};
+use crate::certification::{CertifiedResponses, Hash};
+use crate::content_store::ContentStore;
+use crate::cors::{self, CorsPolicies};
+use crate::domains::{
+    certified_host, ic_domains_file, is_local_host, validate_domain, IC_DOMAINS_PATH,
+};
+use crate::listing;
+use crate::metrics::{self, Metrics};
+use crate::multipart;
+use crate::quota::QuotaTracker;
+use crate::rate_limit::RateLimiter;
+use crate::routing::{Route, RoutingRules};
+use crate::security_headers::SecurityHeaders;
+use crate::signed_url::{self, Purpose, SignedToken, SigningSecret};
+use crate::types::{Asset, AssetEncoding, HttpResponse, StreamingCallbackToken};
+use crate::versions::VersionStore;
+use canister_error::Error;
 
 use crate::rc_bytes::RcBytes;
 use crate::types::*;
-use crate::utils::url_decode;
-use base64::prelude::*;
-use ic_cdk::api::trap;
-
+use crate::utils::{
+    content_disposition, expiry_time, header_value, is_under, query_param, split_url, url_decode,
+    url_encode_path, validate_filename, validate_key,
+};
+use candid::Principal;
 use serde_bytes::ByteBuf;
 use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
+#[derive(Default)]
 pub struct State {
     pub assets: HashMap<AssetKey, Asset>,
-    pub asset_hashes: AssetHashes,
+    pub certified_responses: CertifiedResponses,
+    pub content_store: ContentStore,
+    pub quotas: QuotaTracker,
+    pub rate_limiter: RateLimiter,
+    /// The provision canister, which uploads on behalf of users and is
+    /// therefore exempt from per-caller rate limits.
+    pub provision_canister: Option<Principal>,
+    /// Role members, used to grant access to role-restricted assets.
+    pub roles: HashMap<String, HashSet<Principal>>,
+    /// Key for signing download URLs, generated from `raw_rand` on first use.
+    pub url_signing_secret: Option<SigningSecret>,
+    pub versions: VersionStore,
+    /// Custom domains listed in `/.well-known/ic-domains`; the first one is
+    /// the primary domain used in asset URLs.
+    pub custom_domains: Vec<String>,
+    pub cors: CorsPolicies,
+    /// Whether `POST` and `PUT` requests are accepted as uploads.
+    pub http_uploads_enabled: bool,
+    pub api_keys: ApiKeys,
+    pub routing: RoutingRules,
+    /// Assets served with status 404 for missing paths, by path prefix.
+    pub fallbacks: BTreeMap<String, AssetKey>,
+    /// Prefixes whose paths ending in `/` get a generated index page.
+    pub listing_prefixes: BTreeSet<String>,
+    /// Paths whose generated index page has been certified.
+    certified_listings: HashSet<String>,
+    /// Paths under prefix rules with a `*` destination whose route has been
+    /// certified.
+    certified_routes: HashSet<String>,
+    pub security_headers: SecurityHeaders,
+    pub metrics: Metrics,
+    /// When the current module was installed or last upgraded.
+    pub last_upgrade: u64,
+    /// SHA-256 of the installed module, once `canister_info` has answered.
+    pub module_hash: Option<ByteBuf>,
 }
 
-impl Default for State {
-    fn default() -> Self {
-        State {
-            assets: HashMap::new(),
-            asset_hashes: AssetHashes::default(),
-        }
-    }
-}
+/// Header through which HTTP requests present an API key.
+const API_KEY_HEADER: &str = "X-API-Key";
+
+/// Most index pages certified at once; others are served by update calls.
+const MAX_CERTIFIED_LISTINGS: usize = 1000;
+
+/// Most paths under `*` destination rules certified at once; others are
+/// served by update calls.
+const MAX_CERTIFIED_ROUTES: usize = 1000;
+
+/// Longest lifetime of a signed download URL or upload token.
+const MAX_TOKEN_TTL_SECONDS: u64 = 7 * 24 * 60 * 60;
 
 impl State {
-    pub fn store(&mut self, arg: StoreArg, time: u64) -> Result<(), String> {
+    pub fn store(&mut self, arg: StoreArg, caller: Principal, time: u64) -> Result<(), Error> {
+        self.metrics.store_calls += 1;
+        validate_key(&arg.key)?;
+        if arg.key == IC_DOMAINS_PATH || self.metrics.is_metrics_path(&arg.key) {
+            return Err(Error::invalid_argument("key", "is reserved"));
+        }
+        if let Some(filename) = &arg.filename {
+            validate_filename(filename)?;
+        }
+        let expires_at = arg
+            .ttl_seconds
+            .map(|ttl_seconds| expiry_time(time, ttl_seconds))
+            .transpose()?;
+        if self.routing.is_exact_source(&arg.key) {
+            return Err(Error::invalid_argument(
+                "key",
+                "is the source of a routing rule",
+            ));
+        }
+
+        if !self.is_rate_limit_exempt(&caller) {
+            self.rate_limiter.acquire(caller, time)?;
+        }
+
+        // Compute SHA-256 hash of the content
+        let hash: [u8; 32] = Sha256::digest(&arg.content).into();
+
+        // Verify provided SHA-256 hash if present
+        if let Some(provided_hash) = &arg.sha256 {
+            if hash != provided_hash.as_ref() {
+                return Err(Error::HashMismatch {
+                    expected: provided_hash.clone(),
+                    actual: ByteBuf::from(hash.to_vec()),
+                });
+            }
+        }
+
+        // Only the owner may overwrite an existing key
+        let existing = self.assets.get(&arg.key);
+        if let Some(asset) = existing {
+            if asset.owner != caller {
+                return Err(Error::Unauthorized(format!(
+                    "Asset {} is owned by another principal",
+                    arg.key
+                )));
+            }
+        }
+
+        // Reject the write if the content changed since the caller last saw it
+        let current_sha256 = existing
+            .and_then(|asset| asset.encodings.get(&arg.content_encoding))
+            .map(|encoding| encoding.sha256);
+        let if_match_failed = arg.if_match_sha256.as_ref().is_some_and(|expected| {
+            current_sha256.as_ref().map(|hash| hash.as_slice()) != Some(expected.as_slice())
+        });
+        let if_none_exists_failed = arg.if_none_exists == Some(true) && existing.is_some();
+        if if_match_failed || if_none_exists_failed {
+            return Err(Error::Conflict {
+                current_sha256: current_sha256.map(|hash| ByteBuf::from(hash.to_vec())),
+            });
+        }
+
+        // Enforce the caller's quota against the asset's size after this upload
+        let upload_size = arg.content.len() as u64;
+        let previous_size = existing.map(asset_size);
+        let replaced_size = existing
+            .and_then(|asset| asset.encodings.get(&arg.content_encoding))
+            .map(|encoding| encoding.total_length as u64)
+            .unwrap_or(0);
+        let new_size = previous_size.unwrap_or(0) - replaced_size + upload_size;
+        self.quotas
+            .check(&caller, &arg.key, upload_size, new_size, previous_size)?;
+
+        // Keep the content being replaced in the key's history
+        self.record_version(&arg.key);
+
         // Retrieve or create the asset
         let asset = self.assets.entry(arg.key.clone()).or_insert_with(|| Asset {
             content_type: arg.content_type.clone(),
@@ -37,22 +167,34 @@ impl State {
             headers: None,
             is_aliased: arg.aliased,
             allow_raw_access: None,
+            owner: caller,
+            created: time,
+            visibility: Visibility::Public,
+            expires_at: None,
+            version: 0,
+            filename: None,
         });
 
         // Update asset properties
+        asset.version += 1;
         asset.content_type = arg.content_type.clone();
         asset.is_aliased = arg.aliased;
-
-        // Compute SHA-256 hash of the content
-        let hash = Sha256::digest(&arg.content).into();
-
-        // Verify provided SHA-256 hash if present
-        if let Some(provided_hash) = arg.sha256 {
-            if hash != provided_hash.as_ref() {
-                return Err("SHA-256 hash mismatch".to_string());
-            }
+        if let Some(allow_raw_access) = arg.allow_raw_access {
+            asset.allow_raw_access = Some(allow_raw_access);
+        }
+        if let Some(filename) = arg.filename {
+            asset.filename = Some(filename);
+        }
+        if let Some(visibility) = arg.visibility {
+            asset.visibility = visibility;
+        }
+        if expires_at.is_some() {
+            asset.expires_at = expires_at;
         }
 
+        // Share the bytes with any other encoding holding the same content
+        let content = self.content_store.acquire(hash, arg.content);
+
         // Update or create the encoding
         let encoding = asset
             .encodings
@@ -65,35 +207,503 @@ impl State {
                 sha256: [0; 32],
             });
 
-        encoding.total_length = arg.content.len();
-        encoding.content_chunks = vec![RcBytes::from(arg.content)];
+        // Release the content this encoding pointed at before the overwrite
+        if !encoding.content_chunks.is_empty() {
+            self.content_store.release(&encoding.sha256);
+        }
+
+        encoding.total_length = content.len();
+        encoding.content_chunks = vec![content];
         encoding.modified = time;
         encoding.sha256 = hash;
 
         // Update asset certification
-        let updated_asset_hashes =
-            on_asset_change(std::mem::take(&mut self.asset_hashes), &arg.key, asset);
-        self.asset_hashes = updated_asset_hashes;
+        self.certify_path(&arg.key);
+
+        self.quotas.charge(caller, previous_size, new_size);
+        self.prune_history_to_quota(caller, &arg.key);
 
         Ok(())
     }
 
-    // Add this method to your State implementation
-    fn update_asset_certification(&mut self, key: &str, asset: &Asset) {
-        let updated_asset_hashes =
-            on_asset_change(std::mem::take(&mut self.asset_hashes), key, asset);
-        self.asset_hashes = updated_asset_hashes;
+    /// Describes the `content_encoding` of `key` as it is now stored.
+    pub fn store_result(
+        &self,
+        key: &AssetKey,
+        content_encoding: &str,
+        canister_id: &Principal,
+    ) -> Result<StoreResult, Error> {
+        let asset = self
+            .assets
+            .get(key)
+            .ok_or_else(|| Error::NotFound(format!("Asset {}", key)))?;
+        let encoding = asset
+            .encodings
+            .get(content_encoding)
+            .ok_or_else(|| Error::NotFound(format!("Encoding {}", content_encoding)))?;
+
+        let mut variants: Vec<String> = asset.encodings.keys().cloned().collect();
+        variants.sort();
+
+        Ok(StoreResult {
+            key: key.clone(),
+            sha256: ByteBuf::from(encoding.sha256.to_vec()),
+            size: encoding.total_length as u64,
+            content_type: asset.content_type.clone(),
+            variants,
+            urls: self.asset_urls(key, canister_id),
+        })
+    }
+
+    pub fn asset_urls(&self, key: &str, canister_id: &Principal) -> AssetUrls {
+        let canister_id = canister_id.to_text();
+        AssetUrls {
+            certified: asset_url(&format!("{}.icp0.io", canister_id), key),
+            raw: asset_url(&format!("{}.raw.icp0.io", canister_id), key),
+            custom_domain: self.primary_domain().map(|domain| asset_url(domain, key)),
+        }
     }
 
-    pub fn retrieve(&self, key: &AssetKey) -> Result<Vec<u8>, String> {
+    pub fn primary_domain(&self) -> Option<&String> {
+        self.custom_domains.first()
+    }
+
+    /// Replaces the custom domains, keeping their order, and certifies the
+    /// `/.well-known/ic-domains` file listing them.
+    pub fn set_custom_domains(&mut self, domains: Vec<String>) -> Result<(), Error> {
+        let mut custom_domains: Vec<String> = Vec::with_capacity(domains.len());
+        for domain in domains {
+            validate_domain(&domain)?;
+            if !custom_domains.contains(&domain) {
+                custom_domains.push(domain);
+            }
+        }
+        self.custom_domains = custom_domains;
+
+        // Every asset links to its URL on the primary domain
+        self.certify_all();
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &AssetKey, caller: Principal) -> Result<(), Error> {
+        self.owned_asset(key, &caller)?;
+        self.remove_asset(key);
+        Ok(())
+    }
+
+    /// Removes an asset along with its certification and storage accounting.
+    fn remove_asset(&mut self, key: &AssetKey) {
+        let Some(asset) = self.assets.remove(key) else {
+            return;
+        };
+
+        // Bytes are only freed once the last key referencing them is gone
+        for encoding in asset.encodings.values() {
+            self.content_store.release(&encoding.sha256);
+        }
+        self.quotas
+            .charge_history(asset.owner, self.versions.size_of(key), 0);
+        self.versions.remove(key, &mut self.content_store);
+        self.quotas.release(&asset.owner, asset_size(&asset));
+        self.certify_path(key);
+    }
+
+    /// Content type and encodings of `asset` at `version`, which is either
+    /// the current version or one still kept in its history.
+    fn content_of_version<'a>(
+        &'a self,
+        key: &str,
+        asset: &'a Asset,
+        version: u64,
+    ) -> Option<(&'a String, &'a HashMap<String, AssetEncoding>)> {
+        if version == asset.version {
+            return Some((&asset.content_type, &asset.encodings));
+        }
+        self.versions
+            .get(key, version)
+            .map(|snapshot| (&snapshot.content_type, &snapshot.encodings))
+    }
+
+    pub fn list_versions(
+        &self,
+        key: &AssetKey,
+        caller: &Principal,
+    ) -> Result<Vec<VersionInfo>, Error> {
+        let asset = self
+            .assets
+            .get(key)
+            .ok_or_else(|| Error::NotFound(format!("Asset {}", key)))?;
+        if !self.can_read(asset, caller) {
+            return Err(Error::Unauthorized(format!(
+                "Not authorized to read asset {}",
+                key
+            )));
+        }
+
+        let history = self.versions.list(key).map(|snapshot| {
+            version_info(
+                snapshot.version,
+                &snapshot.content_type,
+                &snapshot.encodings,
+                false,
+            )
+        });
+        let current = version_info(asset.version, &asset.content_type, &asset.encodings, true);
+        Ok(history.chain(std::iter::once(current)).collect())
+    }
+
+    pub fn get_version(
+        &self,
+        key: &AssetKey,
+        version: u64,
+        caller: &Principal,
+    ) -> Result<Vec<u8>, Error> {
         let asset = self
             .assets
             .get(key)
-            .ok_or_else(|| "Asset not found".to_string())?;
+            .ok_or_else(|| Error::NotFound(format!("Asset {}", key)))?;
+        if !self.can_read(asset, caller) {
+            return Err(Error::Unauthorized(format!(
+                "Not authorized to read asset {}",
+                key
+            )));
+        }
+        let (_, encodings) = self
+            .content_of_version(key, asset, version)
+            .ok_or_else(|| Error::NotFound(format!("Version {}", version)))?;
+        let encoding = encodings
+            .get("identity")
+            .ok_or_else(|| Error::NotFound("Identity encoding".to_string()))?;
+
+        Ok(encoding
+            .content_chunks
+            .iter()
+            .flat_map(|chunk| chunk.to_vec())
+            .collect())
+    }
+
+    /// Makes the content of a previous version current again, as a new
+    /// version so the rollback itself can be undone.
+    pub fn rollback(
+        &mut self,
+        key: &AssetKey,
+        version: u64,
+        caller: Principal,
+        time: u64,
+    ) -> Result<(), Error> {
+        let previous_size = asset_size(self.owned_asset(key, &caller)?);
+        let target = self
+            .versions
+            .get(key, version)
+            .cloned()
+            .ok_or_else(|| Error::NotFound(format!("Version {}", version)))?;
+
+        let new_size = target
+            .encodings
+            .values()
+            .map(|encoding| encoding.total_length as u64)
+            .sum();
+        let largest_encoding = target
+            .encodings
+            .values()
+            .map(|encoding| encoding.total_length as u64)
+            .max()
+            .unwrap_or(0);
+        self.quotas.check(
+            &caller,
+            key,
+            largest_encoding,
+            new_size,
+            Some(previous_size),
+        )?;
+
+        // Take the target's references first: recording the current content
+        // may prune the target snapshot and release its blobs
+        for encoding in target.encodings.values() {
+            self.content_store.retain(&encoding.sha256);
+        }
+        self.record_version(key);
+
+        let Some(asset) = self.assets.get_mut(key) else {
+            return Err(Error::NotFound(format!("Asset {}", key)));
+        };
+        for encoding in asset.encodings.values() {
+            self.content_store.release(&encoding.sha256);
+        }
+        asset.version += 1;
+        asset.content_type = target.content_type;
+        asset.encodings = target.encodings;
+        for encoding in asset.encodings.values_mut() {
+            encoding.modified = time;
+        }
+
+        // Re-certify the restored content
+        self.certify_path(key);
+
+        self.quotas.charge(caller, Some(previous_size), new_size);
+        self.prune_history_to_quota(caller, key);
+
+        Ok(())
+    }
+
+    /// Snapshots the current content of `key` into its history, charging
+    /// the snapshot to the asset's owner.
+    fn record_version(&mut self, key: &AssetKey) {
+        let Some(asset) = self.assets.get(key) else {
+            return;
+        };
+        let before = self.versions.size_of(key);
+        self.versions.record(key, asset, &mut self.content_store);
+        self.quotas
+            .charge_history(asset.owner, before, self.versions.size_of(key));
+    }
+
+    /// Drops the oldest versions `owner` keeps, those of `key` first, until
+    /// the owner is back within the storage quota.
+    fn prune_history_to_quota(&mut self, owner: Principal, key: &AssetKey) {
+        if !self.quotas.over_total_bytes(&owner) {
+            return;
+        }
+        let mut keys = vec![key.clone()];
+        keys.extend(
+            self.versions
+                .keys()
+                .filter(|other| *other != key)
+                .filter(|other| {
+                    self.assets
+                        .get(*other)
+                        .is_some_and(|asset| asset.owner == owner)
+                })
+                .cloned(),
+        );
+        for key in keys {
+            while self.quotas.over_total_bytes(&owner) {
+                let before = self.versions.size_of(&key);
+                if !self.versions.prune_oldest(&key, &mut self.content_store) {
+                    break;
+                }
+                self.quotas
+                    .charge_history(owner, before, self.versions.size_of(&key));
+            }
+        }
+    }
+
+    pub fn set_max_versions(&mut self, max_versions: usize) {
+        let before: Vec<(AssetKey, u64)> = self
+            .versions
+            .keys()
+            .map(|key| (key.clone(), self.versions.size_of(key)))
+            .collect();
+        self.versions
+            .set_max_versions(max_versions, &mut self.content_store);
+        for (key, before) in before {
+            if let Some(asset) = self.assets.get(&key) {
+                self.quotas
+                    .charge_history(asset.owner, before, self.versions.size_of(&key));
+            }
+        }
+    }
+
+    /// Deletes every asset whose expiry has passed, returning their keys.
+    pub fn sweep_expired(&mut self, now: u64) -> Vec<AssetKey> {
+        let expired: Vec<AssetKey> = self
+            .assets
+            .iter()
+            .filter(|(_, asset)| asset.expires_at.is_some_and(|expires_at| expires_at <= now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            self.remove_asset(key);
+        }
+        self.api_keys.sweep_expired(now);
+        expired
+    }
+
+    /// Clears the expiry of the given assets so they are kept forever.
+    /// Callers pin their own assets, failing without pinning anything if any
+    /// key is missing or owned by another principal. The provision canister
+    /// pins on behalf of `owner`, skipping the keys `owner` does not own.
+    pub fn pin(
+        &mut self,
+        keys: &[AssetKey],
+        owner: Option<Principal>,
+        caller: Principal,
+    ) -> Result<(), Error> {
+        let owner = match owner {
+            Some(owner) if owner != caller => {
+                if self.provision_canister != Some(caller) {
+                    return Err(Error::Unauthorized(
+                        "Only the provision canister can pin assets of other principals"
+                            .to_string(),
+                    ));
+                }
+                owner
+            }
+            _ => {
+                for key in keys {
+                    self.owned_asset(key, &caller)?;
+                }
+                caller
+            }
+        };
+        for key in keys {
+            if let Some(asset) = self.assets.get_mut(key) {
+                if asset.owner == owner {
+                    asset.expires_at = None;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks up an asset, failing unless `caller` owns it.
+    fn owned_asset(&self, key: &AssetKey, caller: &Principal) -> Result<&Asset, Error> {
+        let asset = self
+            .assets
+            .get(key)
+            .ok_or_else(|| Error::NotFound(format!("Asset {}", key)))?;
+        if asset.owner != *caller {
+            return Err(Error::Unauthorized(format!(
+                "Asset {} is owned by another principal",
+                key
+            )));
+        }
+        Ok(asset)
+    }
+
+    pub fn set_visibility(
+        &mut self,
+        key: &AssetKey,
+        visibility: Visibility,
+        caller: Principal,
+    ) -> Result<(), Error> {
+        self.owned_asset(key, &caller)?;
+        if let Some(asset) = self.assets.get_mut(key) {
+            asset.visibility = visibility;
+        }
+        self.certify_path(key);
+        Ok(())
+    }
+
+    /// Whether `caller` may read `asset` through the authenticated path.
+    fn can_read(&self, asset: &Asset, caller: &Principal) -> bool {
+        if asset.owner == *caller {
+            return true;
+        }
+        match &asset.visibility {
+            Visibility::Public => true,
+            Visibility::OwnerOnly => false,
+            Visibility::Role(role) => self
+                .roles
+                .get(role)
+                .is_some_and(|members| members.contains(caller)),
+        }
+    }
+
+    /// Mints a download token letting `caller` fetch `key` over plain HTTP
+    /// for `ttl_seconds`.
+    pub fn create_download_token(
+        &self,
+        key: &AssetKey,
+        caller: Principal,
+        ttl_seconds: u64,
+        now: u64,
+    ) -> Result<String, Error> {
+        let asset = self
+            .assets
+            .get(key)
+            .ok_or_else(|| Error::NotFound(format!("Asset {}", key)))?;
+        if !self.can_read(asset, &caller) {
+            return Err(Error::Unauthorized(format!(
+                "Not authorized to read asset {}",
+                key
+            )));
+        }
+        self.sign_token(Purpose::Download, key, caller, ttl_seconds, now)
+    }
+
+    /// Mints a token with which `caller` can upload to `key` over HTTP,
+    /// passed as `Authorization: Bearer <token>`.
+    pub fn create_upload_token(
+        &self,
+        key: &AssetKey,
+        caller: Principal,
+        ttl_seconds: u64,
+        now: u64,
+    ) -> Result<String, Error> {
+        validate_key(key)?;
+        if let Some(asset) = self.assets.get(key) {
+            if asset.owner != caller {
+                return Err(Error::Unauthorized(format!(
+                    "Asset {} is owned by another principal",
+                    key
+                )));
+            }
+        }
+        self.sign_token(Purpose::Upload, key, caller, ttl_seconds, now)
+    }
+
+    fn sign_token(
+        &self,
+        purpose: Purpose,
+        key: &AssetKey,
+        caller: Principal,
+        ttl_seconds: u64,
+        now: u64,
+    ) -> Result<String, Error> {
+        if ttl_seconds == 0 || ttl_seconds > MAX_TOKEN_TTL_SECONDS {
+            return Err(Error::invalid_argument(
+                "ttl_seconds",
+                format!("must be between 1 and {}", MAX_TOKEN_TTL_SECONDS),
+            ));
+        }
+        let secret = self
+            .url_signing_secret
+            .as_ref()
+            .ok_or_else(|| Error::Internal("URL signing secret is not initialized".to_string()))?;
+
+        let token = SignedToken {
+            expires_at: now + ttl_seconds * 1_000_000_000,
+            principal: caller,
+        };
+        Ok(signed_url::sign(secret, purpose, key, &token))
+    }
+
+    pub fn grant_role(&mut self, role: String, principal: Principal) {
+        self.roles.entry(role).or_default().insert(principal);
+    }
+
+    pub fn revoke_role(&mut self, role: &str, principal: &Principal) {
+        if let Some(members) = self.roles.get_mut(role) {
+            members.remove(principal);
+            if members.is_empty() {
+                self.roles.remove(role);
+            }
+        }
+    }
+
+    pub fn list_role_members(&self, role: &str) -> Vec<Principal> {
+        self.roles
+            .get(role)
+            .map(|members| members.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn retrieve(&self, key: &AssetKey, caller: &Principal) -> Result<Vec<u8>, Error> {
+        let asset = self
+            .assets
+            .get(key)
+            .ok_or_else(|| Error::NotFound(format!("Asset {}", key)))?;
+        if !self.can_read(asset, caller) {
+            return Err(Error::Unauthorized(format!(
+                "Not authorized to read asset {}",
+                key
+            )));
+        }
         let encoding = asset
             .encodings
             .get("identity")
-            .ok_or_else(|| "No identity encoding".to_string())?;
+            .ok_or_else(|| Error::NotFound("Identity encoding".to_string()))?;
         let content: Vec<u8> = encoding
             .content_chunks
             .iter()
@@ -103,110 +713,1272 @@ impl State {
         Ok(content)
     }
 
-    pub fn list_assets(&self) -> Vec<AssetKey> {
-        self.assets.keys().cloned().collect()
+    /// Keys of the assets `caller` may read.
+    pub fn list_assets(&self, caller: &Principal) -> Vec<AssetKey> {
+        self.assets
+            .iter()
+            .filter(|(_, asset)| self.can_read(asset, caller))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    pub fn list_assets_owned_by(&self, owner: &Principal) -> Vec<AssetKey> {
+        let mut keys: Vec<AssetKey> = self
+            .assets
+            .iter()
+            .filter(|(_, asset)| asset.owner == *owner)
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort();
+        keys
     }
     pub fn handle_http_request(&self, req: HttpRequest, certificate: &[u8]) -> HttpResponse {
-        let path = match url_decode(&req.url) {
-            Ok(decoded_path) => decoded_path,
-            Err(err) => {
-                return HttpResponse {
-                    status_code: 400,
-                    headers: vec![],
-                    body: RcBytes::from(ByteBuf::from(format!("Failed to decode path: {}", err))),
-                    upgrade: None,
-                    streaming_strategy: None,
-                }
+        let (path, query) = split_url(&req.url);
+        let method = req.method.to_ascii_uppercase();
+        if self.metrics.is_metrics_path(path) && (method == "GET" || method == "HEAD") {
+            return self.metrics_response(&req, &method);
+        }
+
+        // Only responses to GET are certified; uploads, preflights, HEAD and
+        // rejected methods are all answered by the update call
+        if method != "GET" {
+            return upgrade_response();
+        }
+
+        // Responses on the raw domain skip certification, so only assets that
+        // opt in are served there
+        if let Some(location) = self.raw_access_redirect(&req, path) {
+            return HttpResponse {
+                status_code: 308,
+                headers: vec![("Location".to_string(), location)],
+                body: RcBytes::default(),
+                upgrade: None,
+                streaming_strategy: None,
+            };
+        }
+
+        // Signed URLs, API keys, old versions and downloads are served from an
+        // update call, whose response needs no certification
+        let needs_upgrade = query_param(query, "token").is_some()
+            || query_param(query, "version").is_some()
+            || query_param(query, "download").as_deref() == Some("1")
+            || header_value(&req.headers, API_KEY_HEADER).is_some();
+        if needs_upgrade {
+            return upgrade_response();
+        }
+        let Ok(path) = url_decode(path) else {
+            return upgrade_response();
+        };
+
+        // So are responses that depend on more of the request than its path:
+        // redirects carrying a query and JSON listings. Echoed origins are
+        // certified along with the request, which has no query
+        let origin = header_value(&req.headers, "Origin");
+        let echoes_origin = self
+            .cors
+            .policy_for(&path)
+            .is_some_and(|policy| cors::origin_header(policy, origin).is_some());
+        let redirects_query =
+            query.is_some() && matches!(self.routing.resolve(&path), Some(Route::Redirect { .. }));
+        let lists_json = wants_json(&req) && self.lists_directory(&path);
+        if (echoes_origin && query.is_some()) || redirects_query || lists_json {
+            return upgrade_response();
+        }
+
+        // Index pages are certified by the update call that generates them,
+        // and any other response that is not certified as it stands now is
+        // left to the update call as well
+        let (mut response, _) = self.path_response(&path, None);
+        self.add_origin_header(&path, origin, &mut response);
+        let Some(certified) = self
+            .certified_responses
+            .get(&path, origin)
+            .filter(|certified| certified.matches(&response))
+        else {
+            return upgrade_response();
+        };
+        let Some(certificate_header) =
+            self.certified_responses
+                .certificate_header(certified, &path, certificate)
+        else {
+            return upgrade_response();
+        };
+
+        let mut response = certified.response.clone();
+        response
+            .headers
+            .push(("IC-Certificate".to_string(), certificate_header));
+        response
+    }
+
+    /// Stores the body of a `POST` or `PUT` request through `store`, on
+    /// behalf of the principal an upload token or API key belongs to. The body is
+    /// either the raw content or a `multipart/form-data` form whose file
+    /// part is stored; a path ending in `/` is completed with its filename.
+    fn upload(
+        &mut self,
+        req: &HttpRequest,
+        canister_id: &Principal,
+        now: u64,
+    ) -> Result<StoreResult, Error> {
+        let (path, _) = split_url(&req.url);
+        let path = url_decode(path).map_err(|err| Error::invalid_argument("url", err))?;
+        let content_type = header_value(&req.headers, "Content-Type")
+            .unwrap_or("application/octet-stream")
+            .to_string();
+
+        let (key, content_type, content, filename) = match multipart::boundary(&content_type) {
+            Some(boundary) => {
+                let parts = multipart::parse(&req.body, &boundary)?;
+                let part = parts
+                    .into_iter()
+                    .find(|part| part.filename.is_some() || part.name.as_deref() == Some("file"))
+                    .ok_or_else(|| Error::invalid_argument("body", "no file part"))?;
+                let key = match (path.ends_with('/'), &part.filename) {
+                    (true, Some(filename)) => format!("{}{}", path, filename),
+                    _ => path,
+                };
+                let content_type = part
+                    .content_type
+                    .unwrap_or_else(|| "application/octet-stream".to_string());
+                (key, content_type, part.content.to_vec(), part.filename)
             }
+            None => (path, content_type, req.body.to_vec(), None),
         };
 
-        let mut response = self.build_http_response(&path);
+        let principal = self.authenticate_upload(req, &key, now)?;
+        let content_encoding = header_value(&req.headers, "Content-Encoding")
+            .unwrap_or("identity")
+            .to_string();
+        let arg = StoreArg {
+            key: key.clone(),
+            content_type,
+            content_encoding: content_encoding.clone(),
+            content: ByteBuf::from(content),
+            sha256: None,
+            aliased: None,
+            visibility: None,
+            ttl_seconds: None,
+            if_match_sha256: None,
+            if_none_exists: None,
+            allow_raw_access: None,
+            filename,
+        };
+        self.store(arg, principal, now)?;
+        self.store_result(&key, &content_encoding, canister_id)
+    }
 
-        // Generate witness
-        let witness = create_asset_witness(&path);
+    /// The principal an upload to `key` is made for, from an API key or a
+    /// bearer upload token.
+    fn authenticate_upload(
+        &self,
+        req: &HttpRequest,
+        key: &str,
+        now: u64,
+    ) -> Result<Principal, Error> {
+        if let Some(api_key) = header_value(&req.headers, API_KEY_HEADER) {
+            return self.api_keys.authenticate(api_key, Access::Write, key, now);
+        }
+        let token = header_value(&req.headers, "Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| Error::Unauthorized("Missing upload token".to_string()))?;
+        let secret = self
+            .url_signing_secret
+            .as_ref()
+            .ok_or_else(|| Error::Unauthorized("Invalid token".to_string()))?;
+        let token = signed_url::verify(secret, Purpose::Upload, key, token.trim(), now)?;
+        Ok(token.principal)
+    }
 
-        // Create IC-Certificate header
-        let ic_certificate_value = format!(
-            "certificate=:{}:, tree=:{}:",
-            BASE64_STANDARD.encode(certificate),
-            BASE64_STANDARD.encode(&witness)
-        );
+    /// Methods `http_request` accepts; writes only while uploads are enabled.
+    pub fn allowed_methods(&self) -> Vec<&'static str> {
+        let mut methods = vec!["GET", "HEAD", "OPTIONS"];
+        if self.http_uploads_enabled {
+            methods.extend(["POST", "PUT"]);
+        }
+        methods
+    }
 
+    fn method_not_allowed(&self) -> HttpResponse {
+        let mut response = error_response(405, "Method not allowed");
         response
             .headers
-            .push(("IC-Certificate".to_string(), ic_certificate_value));
-
+            .push(("Allow".to_string(), self.allowed_methods().join(", ")));
         response
     }
 
-    fn build_http_response(&self, path: &str) -> HttpResponse {
-        if let Some(asset) = self.assets.get(path) {
-            // Get the encoding
-            if let Some(encoding) = asset.encodings.get("identity") {
-                // Verify asset integrity
-                if !verify_asset_integrity(path, encoding.content_chunks[0].as_ref()) {
-                    return HttpResponse {
-                        status_code: 500,
-                        headers: vec![],
-                        body: RcBytes::from(ByteBuf::from("Asset integrity check failed")),
-                        upgrade: None,
-                        streaming_strategy: None,
-                    };
+    /// Serves requests upgraded by `handle_http_request`: `POST` and `PUT`
+    /// uploads, `HEAD` requests and preflights, downloads through signed
+    /// URLs, API keys, `?version=` or `?download=1`, and responses that
+    /// depend on the request's origin or query or are not certified yet,
+    /// such as new index pages. Update responses go through consensus and
+    /// need no certification.
+    pub fn handle_http_request_update(
+        &mut self,
+        req: HttpRequest,
+        canister_id: &Principal,
+        now: u64,
+    ) -> HttpResponse {
+        self.metrics.http_update_requests += 1;
+        let method = req.method.to_ascii_uppercase();
+        if !self.allowed_methods().contains(&method.as_str()) {
+            return self.method_not_allowed();
+        }
+        if is_write_method(&method) {
+            return match self.upload(&req, canister_id, now) {
+                Ok(result) => json_response(200, &upload_json(&result)),
+                Err(err) => {
+                    self.metrics.record_error(&err);
+                    json_response(
+                        err.status_code(),
+                        &serde_json::json!({ "error": err.to_string() }),
+                    )
                 }
+            };
+        }
 
-                // Create asset witness
-                let witness = create_asset_witness(path);
-
-                // Build the response
-                HttpResponse {
-                    status_code: 200,
-                    headers: vec![
-                        ("Content-Type".to_string(), asset.content_type.clone()),
-                        (
-                            "Content-Length".to_string(),
-                            encoding.total_length.to_string(),
-                        ),
-                        (
-                            "IC-Certificate-Witness".to_string(),
-                            BASE64_STANDARD.encode(&witness),
-                        ),
-                        (
-                            "Strict-Transport-Security".to_string(),
-                            "max-age=31536000; includeSubDomains".to_string(),
-                        ),
-                        ("X-Frame-Options".to_string(), "DENY".to_string()),
-                        ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
-                    ],
-                    body: encoding.content_chunks[0].clone(),
-                    upgrade: None,
-                    streaming_strategy: None,
-                }
-            } else {
-                // No suitable encoding found
-                HttpResponse {
-                    status_code: 404,
-                    headers: vec![],
-                    body: RcBytes::from(ByteBuf::from("Asset encoding not found")),
-                    upgrade: None,
-                    streaming_strategy: None,
+        let (path, query) = split_url(&req.url);
+        let path = match url_decode(path) {
+            Ok(decoded_path) => decoded_path,
+            Err(err) => return error_response(400, &format!("Failed to decode path: {}", err)),
+        };
+        let origin = header_value(&req.headers, "Origin");
+        if method == "OPTIONS" {
+            return self.preflight_response(&path, origin);
+        }
+
+        let token = query_param(query, "token");
+        let version = query_param(query, "version");
+        let has_api_key = header_value(&req.headers, API_KEY_HEADER).is_some();
+        let mut response = if token.is_some() || version.is_some() || has_api_key {
+            let response = self
+                .authenticate_reader(&req, &path, token.as_deref(), now)
+                .and_then(|reader| self.serve_upgraded(&path, reader, version.as_deref()));
+            let mut response = match response {
+                Ok(response) => response,
+                Err(err) => {
+                    self.metrics.record_error(&err);
+                    error_response(err.status_code(), &err.to_string())
                 }
-            }
+            };
+            let disposition = self
+                .assets
+                .get(&path)
+                .filter(|_| response.status_code == 200)
+                .and_then(|asset| disposition_header(&path, asset, query));
+            response.headers.extend(disposition);
+            self.add_path_headers(&path, &mut response);
+            response
+        } else if self.lists_directory(&path) && wants_json(&req) {
+            json_response(
+                200,
+                &listing::json(&path, &listing::entries(&self.assets, &path)),
+            )
         } else {
-            // Asset not found
-            HttpResponse {
-                status_code: 404,
-                headers: vec![],
-                body: RcBytes::from(ByteBuf::from("Asset not found")),
+            if self.lists_directory(&path) {
+                self.certify_listing(&path);
+            }
+            self.certify_route(&path);
+            self.path_response(&path, query).0
+        };
+        self.add_origin_header(&path, origin, &mut response);
+        if method == "HEAD" {
+            strip_body(&mut response);
+        }
+        response
+    }
+
+    /// The principal a download is made for, from a signed URL token or an
+    /// API key; `None` for anonymous downloads.
+    fn authenticate_reader(
+        &self,
+        req: &HttpRequest,
+        path: &str,
+        token: Option<&str>,
+        now: u64,
+    ) -> Result<Option<Principal>, Error> {
+        if let Some(token) = token {
+            let secret = self
+                .url_signing_secret
+                .as_ref()
+                .ok_or_else(|| Error::Unauthorized("Invalid download token".to_string()))?;
+            let token = signed_url::verify(secret, Purpose::Download, path, token, now)?;
+            return Ok(Some(token.principal));
+        }
+        match header_value(&req.headers, API_KEY_HEADER) {
+            Some(api_key) => self
+                .api_keys
+                .authenticate(api_key, Access::Read, path, now)
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn serve_upgraded(
+        &self,
+        path: &str,
+        reader: Option<Principal>,
+        version: Option<&str>,
+    ) -> Result<HttpResponse, Error> {
+        let asset = self
+            .assets
+            .get(path)
+            .ok_or_else(|| Error::NotFound(format!("Asset {}", path)))?;
+
+        // Without a signed token or API key only public assets are served
+        // Access is re-checked so revoking a role also revokes issued URLs
+        match reader {
+            Some(reader) if !self.can_read(asset, &reader) => {
+                return Err(Error::Unauthorized(format!(
+                    "Not authorized to read asset {}",
+                    path
+                )));
+            }
+            Some(_) => {}
+            None if asset.visibility != Visibility::Public => {
+                return Err(Error::Unauthorized("Asset is private".to_string()));
+            }
+            None => {}
+        }
+
+        let (content_type, encodings) = match version {
+            Some(version) => {
+                let version: u64 = version
+                    .parse()
+                    .map_err(|_| Error::invalid_argument("version", "must be a number"))?;
+                self.content_of_version(path, asset, version)
+                    .ok_or_else(|| Error::NotFound(format!("Version {}", version)))?
+            }
+            None => (&asset.content_type, &asset.encodings),
+        };
+        let encoding = encodings
+            .get("identity")
+            .ok_or_else(|| Error::NotFound("Identity encoding".to_string()))?;
+
+        // Old versions never change, but signed URLs must not outlive their token
+        let cache_control = match reader {
+            Some(_) => "private, no-store",
+            None => "public, max-age=31536000, immutable",
+        };
+
+        Ok(HttpResponse {
+            status_code: 200,
+            headers: vec![
+                ("Content-Type".to_string(), content_type.clone()),
+                (
+                    "Content-Length".to_string(),
+                    encoding.total_length.to_string(),
+                ),
+                ("Cache-Control".to_string(), cache_control.to_string()),
+                ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+            ],
+            body: encoding.content_chunks[0].clone(),
+            upgrade: None,
+            streaming_strategy: None,
+        })
+    }
+
+    /// Replaces the routing rules. Exact rules are certified under their
+    /// source path, which therefore must not hold an asset.
+    pub fn set_routing_rules(&mut self, rules: Vec<RoutingRule>) -> Result<(), Error> {
+        if let Some(rule) = rules
+            .iter()
+            .find(|rule| rule.source == IC_DOMAINS_PATH || self.assets.contains_key(&rule.source))
+        {
+            return Err(Error::invalid_argument(
+                "source",
+                format!("an asset is stored at {}", rule.source),
+            ));
+        }
+        self.routing.set(rules)?;
+        let routes = std::mem::take(&mut self.certified_routes);
+        self.certified_routes = routes
+            .into_iter()
+            .filter(|path| self.can_certify_route(path))
+            .collect();
+        self.certify_all();
+        Ok(())
+    }
+
+    /// Sets where metrics are served. The path must not hide an asset.
+    pub fn set_metrics_config(&mut self, config: MetricsConfig) -> Result<(), Error> {
+        if let Some(path) = &config.path {
+            if path == IC_DOMAINS_PATH || self.assets.contains_key(path) {
+                return Err(Error::invalid_argument(
+                    "path",
+                    format!("an asset is stored at {}", path),
+                ));
+            }
+        }
+        self.metrics.set_config(config)
+    }
+
+    /// Sets the asset served for missing paths under `prefix`, or removes
+    /// it when `key` is `None`. The prefix `/` sets the default.
+    pub fn set_fallback(&mut self, prefix: String, key: Option<AssetKey>) -> Result<(), Error> {
+        if !prefix.starts_with('/') {
+            return Err(Error::invalid_argument("prefix", "must start with '/'"));
+        }
+        match key {
+            Some(key) => {
+                validate_key(&key)?;
+                self.fallbacks.insert(prefix, key);
+            }
+            None => {
+                self.fallbacks.remove(&prefix);
+            }
+        }
+        self.certify_all();
+        Ok(())
+    }
+
+    /// The 404 response serving the fallback for `path` under the longest
+    /// matching prefix. Only public assets are used as fallbacks.
+    fn fallback_for(&self, path: &str) -> Option<(HttpResponse, Option<Hash>)> {
+        let key = self
+            .fallbacks
+            .iter()
+            .rev()
+            .find(|(prefix, _)| is_under(path, prefix))
+            .map(|(_, key)| key)?;
+        let asset = self
+            .assets
+            .get(key)
+            .filter(|asset| asset.visibility == Visibility::Public)?;
+        let encoding = asset.encodings.get("identity")?;
+
+        let response = HttpResponse {
+            status_code: 404,
+            headers: vec![
+                ("Content-Type".to_string(), asset.content_type.clone()),
+                (
+                    "Content-Length".to_string(),
+                    encoding.total_length.to_string(),
+                ),
+                ("Cache-Control".to_string(), "no-cache".to_string()),
+                ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+            ],
+            body: encoding.content_chunks[0].clone(),
+            upgrade: None,
+            streaming_strategy: None,
+        };
+        Some((response, Some(encoding.sha256)))
+    }
+
+    /// Enables or disables index pages for paths under `prefix`.
+    pub fn set_directory_listing(&mut self, prefix: String, enabled: bool) -> Result<(), Error> {
+        if !prefix.starts_with('/') {
+            return Err(Error::invalid_argument("prefix", "must start with '/'"));
+        }
+        if enabled {
+            self.listing_prefixes.insert(prefix);
+            return Ok(());
+        }
+
+        self.listing_prefixes.remove(&prefix);
+        let disabled: Vec<String> = self
+            .certified_listings
+            .iter()
+            .filter(|path| !self.lists_directory(path))
+            .cloned()
+            .collect();
+        for path in disabled {
+            self.certified_listings.remove(&path);
+            self.certify_exact(&path);
+        }
+        Ok(())
+    }
+
+    /// Whether `path` is answered with a generated index page: it ends in
+    /// `/`, is under an enabled prefix, and has no asset of its own.
+    fn lists_directory(&self, path: &str) -> bool {
+        path.ends_with('/')
+            && self
+                .listing_prefixes
+                .iter()
+                .any(|prefix| path.starts_with(prefix.as_str()))
+            && !self.assets.contains_key(path)
+            && !self.assets.contains_key(&format!("{}index.html", path))
+    }
+
+    /// Certifies the HTML index page for `path`, so later queries can serve
+    /// it without an update call. Anyone can request any path, so only
+    /// directories with something to list are certified, and only so many.
+    fn certify_listing(&mut self, path: &str) {
+        let is_full = self.certified_listings.len() >= MAX_CERTIFIED_LISTINGS;
+        if !listing::has_entries(&self.assets, path)
+            || (is_full && !self.certified_listings.contains(path))
+        {
+            return;
+        }
+        self.certified_listings.insert(path.to_string());
+        self.certify_exact(path);
+    }
+
+    /// Re-certifies the index page for `path` after its entries changed,
+    /// dropping it once the directory is empty.
+    fn recertify_listing(&mut self, path: &str) {
+        if !listing::has_entries(&self.assets, path) {
+            self.certified_listings.remove(path);
+        }
+        self.certify_exact(path);
+    }
+
+    /// Certifies the route for `path` if a prefix rule gives it one of its
+    /// own, so old links keep being served by queries. Like listings, only
+    /// routes to something and only so many are certified.
+    fn certify_route(&mut self, path: &str) {
+        let is_full = self.certified_routes.len() >= MAX_CERTIFIED_ROUTES;
+        if !self.can_certify_route(path) || (is_full && !self.certified_routes.contains(path)) {
+            return;
+        }
+        self.certified_routes.insert(path.to_string());
+        self.certify_exact(path);
+    }
+
+    /// Whether `path` has a route of its own: a redirect, or a rewrite to an
+    /// asset that exists.
+    fn can_certify_route(&self, path: &str) -> bool {
+        if !self.routing.routes_by_suffix(path) {
+            return false;
+        }
+        match self.routing.resolve(path) {
+            Some(Route::Rewrite(target)) => self.assets.contains_key(&target),
+            _ => true,
+        }
+    }
+
+    /// Serves metrics in the Prometheus text format. They change with every
+    /// call and are therefore not certified, so scrapers should use the raw
+    /// domain, which is not redirected for this path.
+    fn metrics_response(&self, req: &HttpRequest, method: &str) -> HttpResponse {
+        if !self.metrics.authorizes(req) {
+            let mut response = error_response(401, "Unauthorized");
+            response
+                .headers
+                .push(("WWW-Authenticate".to_string(), "Bearer".to_string()));
+            return response;
+        }
+        let body = match metrics::encode(self, ic_cdk::api::time()) {
+            Ok(body) => body,
+            Err(err) => return error_response(500, &format!("Failed to encode metrics: {}", err)),
+        };
+        let mut response = HttpResponse {
+            status_code: 200,
+            headers: vec![
+                (
+                    "Content-Type".to_string(),
+                    "text/plain; version=0.0.4".to_string(),
+                ),
+                ("Content-Length".to_string(), body.len().to_string()),
+                ("Cache-Control".to_string(), "no-store".to_string()),
+            ],
+            body: RcBytes::from(ByteBuf::from(body)),
+            upgrade: None,
+            streaming_strategy: None,
+        };
+        if method == "HEAD" {
+            strip_body(&mut response);
+        }
+        response
+    }
+
+    /// Answers a CORS preflight for `path` under the matching policy.
+    fn preflight_response(&self, path: &str, origin: Option<&str>) -> HttpResponse {
+        let headers = self
+            .cors
+            .policy_for(path)
+            .and_then(|policy| cors::preflight_headers(policy, origin));
+        match headers {
+            Some(headers) => HttpResponse {
+                status_code: 204,
+                headers,
+                body: RcBytes::default(),
                 upgrade: None,
                 streaming_strategy: None,
+            },
+            None => error_response(403, "Cross-origin request not allowed"),
+        }
+    }
+
+    /// Headers that depend on nothing but the path: its security headers and
+    /// the part of its CORS policy that is the same for every origin.
+    fn add_path_headers(&self, path: &str, response: &mut HttpResponse) {
+        response
+            .headers
+            .extend(self.security_headers.headers_for(path));
+        if let Some(policy) = self.cors.policy_for(path) {
+            response.headers.extend(cors::response_headers(policy));
+        }
+    }
+
+    /// `response` for `path` as served to each origin its CORS policy
+    /// echoes.
+    fn origin_responses(&self, path: &str, response: &HttpResponse) -> Vec<(String, HttpResponse)> {
+        let Some(policy) = self.cors.policy_for(path) else {
+            return vec![];
+        };
+        cors::echoed_origins(policy)
+            .map(|origin| {
+                let mut response = response.clone();
+                self.add_origin_header(path, Some(origin), &mut response);
+                (origin.clone(), response)
+            })
+            .collect()
+    }
+
+    /// Echoes `origin` if the CORS policy for `path` lists it by name.
+    fn add_origin_header(&self, path: &str, origin: Option<&str>, response: &mut HttpResponse) {
+        if let Some(policy) = self.cors.policy_for(path) {
+            response.headers.extend(cors::origin_header(policy, origin));
+        }
+    }
+
+    /// Where to redirect `req` if it arrived on a raw domain for an asset
+    /// that does not allow raw access.
+    fn raw_access_redirect(&self, req: &HttpRequest, path: &str) -> Option<String> {
+        let host = header_value(&req.headers, "Host")?;
+        let certified_host = certified_host(host)?;
+
+        let allows_raw_access = url_decode(path)
+            .ok()
+            .and_then(|path| self.assets.get(&path))
+            .is_some_and(|asset| asset.allow_raw_access == Some(true));
+        if allows_raw_access {
+            return None;
+        }
+
+        let scheme = if is_local_host(host) { "http" } else { "https" };
+        Some(format!("{}://{}{}", scheme, certified_host, req.url))
+    }
+
+    /// The response to a `GET` for `path`, as far as it depends on the path
+    /// alone, which is what gets certified. `query` carries over to
+    /// redirects and may ask for a download.
+    fn path_response(&self, path: &str, query: Option<&str>) -> (HttpResponse, Option<Hash>) {
+        let (mut response, body_sha256) = if path == IC_DOMAINS_PATH
+            && !self.custom_domains.is_empty()
+        {
+            let response = HttpResponse {
+                status_code: 200,
+                headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+                body: RcBytes::from(ByteBuf::from(ic_domains_file(&self.custom_domains))),
+                upgrade: None,
+                streaming_strategy: None,
+            };
+            (response, None)
+        } else {
+            // Rewritten requests are answered as the target path
+            match self.routing.resolve(path) {
+                Some(Route::Redirect {
+                    status_code,
+                    location,
+                }) => (redirect_response(status_code, location, query), None),
+                Some(Route::Rewrite(target)) => self.content_response(&target, query),
+                None if self.lists_directory(path) => {
+                    let html = listing::html(path, &listing::entries(&self.assets, path));
+                    let response = listing_response("text/html; charset=utf-8", html.into_bytes());
+                    (response, None)
+                }
+                None => self.content_response(path, query),
+            }
+        };
+        self.add_path_headers(path, &mut response);
+        (response, body_sha256)
+    }
+
+    /// The response shared by the paths under `prefix` that have none of
+    /// their own, or `None` if a prefix rule gives each a different one.
+    fn missing_response(&self, prefix: &str) -> Option<(HttpResponse, Option<Hash>)> {
+        let (mut response, body_sha256) = match self.routing.route_below(prefix)? {
+            Some(Route::Redirect {
+                status_code,
+                location,
+            }) => (redirect_response(status_code, location, None), None),
+            Some(Route::Rewrite(target)) => self.content_response(&target, None),
+            None => self.not_found_response(prefix),
+        };
+        self.add_path_headers(prefix, &mut response);
+        Some((response, body_sha256))
+    }
+
+    /// The asset stored at `key`, or the 404 for its path if there is none.
+    fn content_response(&self, key: &str, query: Option<&str>) -> (HttpResponse, Option<Hash>) {
+        let Some(asset) = self.assets.get(key) else {
+            return self.not_found_response(key);
+        };
+
+        // Private assets are only available through `retrieve`
+        if asset.visibility != Visibility::Public {
+            return (error_response(403, "Asset is private"), None);
+        }
+        let Some(encoding) = asset.encodings.get("identity") else {
+            return (error_response(404, "Asset encoding not found"), None);
+        };
+
+        let mut headers = vec![
+            ("Content-Type".to_string(), asset.content_type.clone()),
+            (
+                "Content-Length".to_string(),
+                encoding.total_length.to_string(),
+            ),
+            ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+        ];
+        if let Some(domain) = self.primary_domain() {
+            headers.push((
+                "Link".to_string(),
+                format!("<{}>; rel=\"canonical\"", asset_url(domain, key)),
+            ));
+        }
+        headers.extend(disposition_header(key, asset, query));
+        let response = HttpResponse {
+            status_code: 200,
+            headers,
+            body: encoding.content_chunks[0].clone(),
+            upgrade: None,
+            streaming_strategy: None,
+        };
+        (response, Some(encoding.sha256))
+    }
+
+    /// The fallback for `path`, or a plain 404.
+    fn not_found_response(&self, path: &str) -> (HttpResponse, Option<Hash>) {
+        self.fallback_for(path)
+            .unwrap_or_else(|| (error_response(404, "Asset not found"), None))
+    }
+
+    /// Whether `path` is certified with a response of its own rather than
+    /// through the wildcard for one of its prefixes.
+    fn has_exact_response(&self, path: &str) -> bool {
+        self.assets.contains_key(path)
+            || self.routing.is_exact_source(path)
+            || self.certified_listings.contains(path)
+            || self.certified_routes.contains(path)
+            || (path == IC_DOMAINS_PATH && !self.custom_domains.is_empty())
+    }
+
+    fn certify_exact(&mut self, path: &str) {
+        if self.has_exact_response(path) {
+            let (response, body_sha256) = self.path_response(path, None);
+            let by_origin = self.origin_responses(path, &response);
+            self.certified_responses
+                .certify_exact(path, response, body_sha256, by_origin);
+        } else {
+            self.certified_responses.remove_exact(path);
+        }
+    }
+
+    /// Re-certifies what is served at `path` after the asset there changed,
+    /// along with the responses that include it.
+    fn certify_path(&mut self, path: &str) {
+        // Rewrites and fallbacks serve the asset under other paths too
+        if self.routing.targets(path) || self.fallbacks.values().any(|key| key == path) {
+            self.certify_all();
+            return;
+        }
+        self.certify_exact(path);
+        let listings: Vec<String> = self
+            .certified_listings
+            .iter()
+            .filter(|listing| path.starts_with(listing.as_str()))
+            .cloned()
+            .collect();
+        for listing in listings {
+            self.recertify_listing(&listing);
+        }
+
+        // Certified routes may rewrite to the asset, or stop doing so
+        // because it is gone
+        let routes: Vec<String> = self
+            .certified_routes
+            .iter()
+            .filter(|route| {
+                matches!(self.routing.resolve(route), Some(Route::Rewrite(target)) if target == path)
+            })
+            .cloned()
+            .collect();
+        for route in routes {
+            if !self.can_certify_route(&route) {
+                self.certified_routes.remove(&route);
+            }
+            self.certify_exact(&route);
+        }
+    }
+
+    /// Re-certifies every response, after a change that applies across
+    /// paths.
+    pub fn certify_all(&mut self) {
+        self.certified_responses.clear();
+        let mut paths: Vec<String> = self.assets.keys().cloned().collect();
+        paths.extend(self.routing.exact_sources().cloned());
+        paths.extend(self.certified_listings.iter().cloned());
+        paths.extend(self.certified_routes.iter().cloned());
+        paths.push(IC_DOMAINS_PATH.to_string());
+        for path in paths {
+            self.certify_exact(&path);
+        }
+
+        // Every prefix whose paths may be answered differently gets a
+        // wildcard; `*` rules cover the same paths as `/`
+        let mut prefixes = BTreeSet::from(["/".to_string()]);
+        prefixes.extend(self.fallbacks.keys().cloned());
+        prefixes.extend(self.cors.prefixes().cloned());
+        prefixes.extend(self.security_headers.override_prefixes().cloned());
+        prefixes.extend(
+            self.routing
+                .prefixes()
+                .filter(|prefix| !prefix.is_empty())
+                .map(str::to_string),
+        );
+        for prefix in prefixes {
+            if let Some((response, body_sha256)) = self.missing_response(&prefix) {
+                let by_origin = self.origin_responses(&prefix, &response);
+                self.certified_responses.certify_wildcard(
+                    &prefix,
+                    response,
+                    body_sha256,
+                    by_origin,
+                );
             }
         }
     }
+
+    pub fn set_cors_policy(
+        &mut self,
+        prefix: String,
+        policy: Option<CorsPolicy>,
+    ) -> Result<(), Error> {
+        self.cors.set(prefix, policy)?;
+        self.certify_all();
+        Ok(())
+    }
+
+    pub fn set_security_headers(&mut self, policy: SecurityHeaderPolicy) -> Result<(), Error> {
+        self.security_headers.set_default(policy)?;
+        self.certify_all();
+        Ok(())
+    }
+
+    pub fn set_security_header_override(
+        &mut self,
+        prefix: String,
+        policy: Option<SecurityHeaderPolicy>,
+    ) -> Result<(), Error> {
+        self.security_headers.set_override(prefix, policy)?;
+        self.certify_all();
+        Ok(())
+    }
+
+    fn is_rate_limit_exempt(&self, caller: &Principal) -> bool {
+        self.provision_canister.as_ref() == Some(caller)
+    }
+
+    /// Whether an upload from `caller` would currently pass the rate limit.
+    pub fn can_upload(&self, caller: &Principal, now: u64) -> bool {
+        self.is_rate_limit_exempt(caller) || self.rate_limiter.allows(caller, now)
+    }
+
+    /// Cheap pre-execution checks for a `store` ingress message.
+    pub fn inspect_store(&self, arg: &StoreArg, caller: &Principal, now: u64) -> bool {
+        let within_size = match self.quotas.config.max_asset_size {
+            Some(max) => arg.content.len() as u64 <= max,
+            None => true,
+        };
+        let may_write = match self.assets.get(&arg.key) {
+            Some(asset) => asset.owner == *caller,
+            None => true,
+        };
+        within_size && may_write && validate_key(&arg.key).is_ok() && self.can_upload(caller, now)
+    }
+
+    pub fn get_usage(&self, principal: &Principal) -> Usage {
+        self.quotas.usage_of(principal)
+    }
+
     pub fn handle_streaming_callback(
         &self,
-        token: StreamingCallbackToken,
+        _token: StreamingCallbackToken,
     ) -> Result<StreamingCallbackHttpResponse, String> {
         // Implement streaming logic if needed
         Err("Streaming not implemented".to_string())
     }
 }
+
+pub fn is_write_method(method: &str) -> bool {
+    method == "POST" || method == "PUT"
+}
+
+/// Turns a response into the answer to a `HEAD` request, keeping the
+/// `Content-Length` of the body it would have had.
+fn strip_body(response: &mut HttpResponse) {
+    let has_length = header_value(&response.headers, "Content-Length").is_some();
+    if !has_length {
+        response.headers.push((
+            "Content-Length".to_string(),
+            response.body.len().to_string(),
+        ));
+    }
+    response.body = RcBytes::default();
+}
+
+/// Tells the HTTP gateway to retry the request as an update call.
+fn upgrade_response() -> HttpResponse {
+    HttpResponse {
+        status_code: 200,
+        headers: vec![],
+        body: RcBytes::default(),
+        upgrade: Some(true),
+        streaming_strategy: None,
+    }
+}
+
+fn wants_json(req: &HttpRequest) -> bool {
+    header_value(&req.headers, "Accept").is_some_and(|accept| accept.contains("application/json"))
+}
+
+fn listing_response(content_type: &str, body: Vec<u8>) -> HttpResponse {
+    HttpResponse {
+        status_code: 200,
+        headers: vec![
+            ("Content-Type".to_string(), content_type.to_string()),
+            ("Content-Length".to_string(), body.len().to_string()),
+            ("Cache-Control".to_string(), "no-cache".to_string()),
+            ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+        ],
+        body: RcBytes::from(ByteBuf::from(body)),
+        upgrade: None,
+        streaming_strategy: None,
+    }
+}
+
+/// A redirect to `location`, carrying over the request's query string.
+fn redirect_response(status_code: u16, location: String, query: Option<&str>) -> HttpResponse {
+    let location = match query {
+        Some(query) if !location.contains('?') => format!("{}?{}", location, query),
+        _ => location,
+    };
+    HttpResponse {
+        status_code,
+        headers: vec![("Location".to_string(), location)],
+        body: RcBytes::default(),
+        upgrade: None,
+        streaming_strategy: None,
+    }
+}
+
+fn json_response(status_code: u16, body: &serde_json::Value) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+        body: RcBytes::from(ByteBuf::from(body.to_string())),
+        upgrade: None,
+        streaming_strategy: None,
+    }
+}
+
+fn upload_json(result: &StoreResult) -> serde_json::Value {
+    let sha256: String = result
+        .sha256
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let url = result
+        .urls
+        .custom_domain
+        .as_ref()
+        .unwrap_or(&result.urls.certified);
+    serde_json::json!({
+        "key": result.key,
+        "sha256": sha256,
+        "size": result.size,
+        "content_type": result.content_type,
+        "variants": result.variants,
+        "url": url,
+        "urls": {
+            "certified": result.urls.certified,
+            "raw": result.urls.raw,
+            "custom_domain": result.urls.custom_domain,
+        },
+    })
+}
+
+/// The `Content-Disposition` naming the file an asset is saved as;
+/// `?download=1` saves it rather than displays it. Assets without a filename
+/// only get one when downloaded, named after the last segment of their key.
+fn disposition_header(key: &str, asset: &Asset, query: Option<&str>) -> Option<(String, String)> {
+    let attachment = query_param(query, "download").as_deref() == Some("1");
+    let filename = match &asset.filename {
+        Some(filename) => filename.as_str(),
+        None if attachment => key.rsplit('/').next().unwrap_or(key),
+        None => return None,
+    };
+    if filename.is_empty() {
+        return None;
+    }
+    Some((
+        "Content-Disposition".to_string(),
+        content_disposition(attachment, filename),
+    ))
+}
+
+/// URL of `key` on `host`.
+fn asset_url(host: &str, key: &str) -> String {
+    format!("https://{}{}", host, url_encode_path(key))
+}
+
+/// Total bytes held by all encodings of an asset.
+fn asset_size(asset: &Asset) -> u64 {
+    asset
+        .encodings
+        .values()
+        .map(|encoding| encoding.total_length as u64)
+        .sum()
+}
+
+fn error_response(status_code: u16, msg: &str) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![],
+        body: RcBytes::from(ByteBuf::from(msg)),
+        upgrade: None,
+        streaming_strategy: None,
+    }
+}
+
+fn version_info(
+    version: u64,
+    content_type: &str,
+    encodings: &HashMap<String, AssetEncoding>,
+    current: bool,
+) -> VersionInfo {
+    let identity = encodings.get("identity");
+    VersionInfo {
+        version,
+        content_type: content_type.to_string(),
+        sha256: identity.map(|encoding| ByteBuf::from(encoding.sha256.to_vec())),
+        size: identity.map_or(0, |encoding| encoding.total_length as u64),
+        modified: encodings
+            .values()
+            .map(|encoding| encoding.modified)
+            .max()
+            .unwrap_or(0),
+        current,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owner() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn store_arg(key: &str, content: &[u8]) -> StoreArg {
+        StoreArg {
+            key: key.to_string(),
+            content_type: "text/plain".to_string(),
+            content_encoding: "identity".to_string(),
+            content: ByteBuf::from(content.to_vec()),
+            sha256: None,
+            aliased: None,
+            visibility: None,
+            ttl_seconds: None,
+            if_match_sha256: None,
+            if_none_exists: None,
+            allow_raw_access: None,
+            filename: None,
+        }
+    }
+
+    fn request(url: &str) -> HttpRequest {
+        HttpRequest {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            headers: vec![],
+            body: ByteBuf::new(),
+        }
+    }
+
+    fn get_update(state: &mut State, url: &str) -> HttpResponse {
+        state.handle_http_request_update(request(url), &Principal::anonymous(), 0)
+    }
+
+    #[test]
+    fn private_assets_are_read_by_owners_and_role_members() {
+        let reader = Principal::from_slice(&[2]);
+        let mut state = State::default();
+        for (key, visibility) in [
+            ("/owner.txt", Visibility::OwnerOnly),
+            ("/role.txt", Visibility::Role("editors".to_string())),
+        ] {
+            let mut arg = store_arg(key, b"a");
+            arg.visibility = Some(visibility);
+            state.store(arg, owner(), 0).unwrap();
+        }
+        let can_read = |state: &State, key: &str, caller: &Principal| {
+            state.can_read(&state.assets[key], caller)
+        };
+
+        assert!(can_read(&state, "/owner.txt", &owner()));
+        assert!(!can_read(&state, "/owner.txt", &reader));
+        assert!(can_read(&state, "/role.txt", &owner()));
+        assert!(!can_read(&state, "/role.txt", &reader));
+
+        state.grant_role("editors".to_string(), reader);
+        assert!(can_read(&state, "/role.txt", &reader));
+        assert!(!can_read(&state, "/owner.txt", &reader));
+
+        state.revoke_role("editors", &reader);
+        assert!(!can_read(&state, "/role.txt", &reader));
+    }
+
+    #[test]
+    fn listed_assets_are_those_the_caller_can_read() {
+        let mut state = State::default();
+        state
+            .store(store_arg("/public.txt", b"a"), owner(), 0)
+            .unwrap();
+        let mut arg = store_arg("/private.txt", b"a");
+        arg.visibility = Some(Visibility::OwnerOnly);
+        state.store(arg, owner(), 0).unwrap();
+
+        let mut keys = state.list_assets(&owner());
+        keys.sort();
+        assert_eq!(keys, ["/private.txt", "/public.txt"]);
+        assert_eq!(state.list_assets(&Principal::anonymous()), ["/public.txt"]);
+    }
+
+    #[test]
+    fn only_listings_with_entries_are_certified() {
+        let mut state = State::default();
+        state.certify_all();
+        state
+            .set_directory_listing("/cars/".to_string(), true)
+            .unwrap();
+        state
+            .store(store_arg("/cars/a/1.png", b"1"), owner(), 0)
+            .unwrap();
+
+        for i in 0..10 {
+            get_update(&mut state, &format!("/cars/x{}/", i));
+        }
+        get_update(&mut state, "/cars/a/");
+        get_update(&mut state, "/cars/");
+        assert_eq!(state.certified_listings.len(), 2);
+
+        // Emptying a directory drops its listing
+        state.delete(&"/cars/a/1.png".to_string(), owner()).unwrap();
+        assert!(state.certified_listings.is_empty());
+        let certified = state.certified_responses.get("/cars/a/", None).unwrap();
+        assert_eq!(certified.response.status_code, 404);
+    }
+
+    #[test]
+    fn provision_pins_only_assets_of_the_owner() {
+        let provision = Principal::from_slice(&[2]);
+        let other = Principal::from_slice(&[3]);
+        let mut state = State {
+            provision_canister: Some(provision),
+            ..State::default()
+        };
+        for (key, principal) in [("/a.png", owner()), ("/b.png", other)] {
+            let mut arg = store_arg(key, b"a");
+            arg.ttl_seconds = Some(60);
+            state.store(arg, principal, 0).unwrap();
+        }
+        let keys = [
+            "/a.png".to_string(),
+            "/b.png".to_string(),
+            "/c.png".to_string(),
+        ];
+
+        assert!(state.pin(&keys, Some(owner()), other).is_err());
+        assert!(state.pin(&keys, None, owner()).is_err());
+        state.pin(&keys, Some(owner()), provision).unwrap();
+        assert_eq!(state.assets["/a.png"].expires_at, None);
+        assert!(state.assets["/b.png"].expires_at.is_some());
+    }
+
+    #[test]
+    fn star_routes_are_certified_on_first_hit() {
+        let mut state = State::default();
+        let rule = |source: &str, destination: &str, action| RoutingRule {
+            source: source.to_string(),
+            destination: destination.to_string(),
+            action,
+        };
+        state
+            .set_routing_rules(vec![
+                rule(
+                    "/old/*",
+                    "/new/*",
+                    RuleAction::Redirect { status_code: 301 },
+                ),
+                rule("/img/*", "/images/*", RuleAction::Rewrite),
+            ])
+            .unwrap();
+        state
+            .store(store_arg("/images/a.png", b"a"), owner(), 0)
+            .unwrap();
+
+        get_update(&mut state, "/old/a.html");
+        get_update(&mut state, "/img/a.png");
+        get_update(&mut state, "/img/missing.png");
+        assert_eq!(state.certified_routes.len(), 2);
+        let certified = state.certified_responses.get("/old/a.html", None).unwrap();
+        assert_eq!(certified.response.status_code, 301);
+        let certified = state.certified_responses.get("/img/a.png", None).unwrap();
+        assert_eq!(&*certified.response.body, b"a");
+
+        // Deleting the target drops the rewrite to it
+        state.delete(&"/images/a.png".to_string(), owner()).unwrap();
+        assert_eq!(state.certified_routes.len(), 1);
+        state.set_routing_rules(vec![]).unwrap();
+        assert!(state.certified_routes.is_empty());
+    }
+
+    #[test]
+    fn allowed_origins_are_served_certified() {
+        let origin = "https://app.example.com";
+        let mut state = State::default();
+        state
+            .set_cors_policy(
+                "/".to_string(),
+                Some(CorsPolicy {
+                    allowed_origins: vec![origin.to_string()],
+                    allowed_methods: vec!["GET".to_string()],
+                    allowed_headers: vec![],
+                    max_age_seconds: None,
+                }),
+            )
+            .unwrap();
+        state.store(store_arg("/a.png", b"a"), owner(), 0).unwrap();
+
+        let mut req = request("/a.png");
+        req.headers.push(("Origin".to_string(), origin.to_string()));
+        let response = state.handle_http_request(req.clone(), b"certificate");
+        assert_eq!(response.upgrade, None);
+        assert_eq!(
+            header_value(&response.headers, "Access-Control-Allow-Origin"),
+            Some(origin)
+        );
+        assert!(header_value(&response.headers, "IC-Certificate").is_some());
+
+        req.url = "/a.png?v=1".to_string();
+        let response = state.handle_http_request(req, b"certificate");
+        assert_eq!(response.upgrade, Some(true));
+    }
+
+    #[test]
+    fn certified_listings_are_capped() {
+        let mut state = State::default();
+        state.set_directory_listing("/".to_string(), true).unwrap();
+        for i in 0..=MAX_CERTIFIED_LISTINGS {
+            let key = format!("/d{}/a.txt", i);
+            state.store(store_arg(&key, b"a"), owner(), 0).unwrap();
+            get_update(&mut state, &format!("/d{}/", i));
+        }
+        assert_eq!(state.certified_listings.len(), MAX_CERTIFIED_LISTINGS);
+    }
+}
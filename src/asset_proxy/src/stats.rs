@@ -0,0 +1,50 @@
+// src/stats.rs
+//! Storage and canister statistics served by the `stats` query.
+
+use crate::metrics::{heap_memory_bytes, stable_memory_bytes};
+use crate::state::State;
+use crate::types::{AssetSize, Stats};
+use candid::Nat;
+use std::collections::BTreeMap;
+
+/// How many of the largest assets are listed.
+const LARGEST_ASSETS: usize = 10;
+
+pub fn stats(state: &State) -> Stats {
+    let mut bytes_by_content_type: BTreeMap<String, u64> = BTreeMap::new();
+    let mut sizes: Vec<AssetSize> = Vec::with_capacity(state.assets.len());
+    for (key, asset) in &state.assets {
+        let size: u64 = asset
+            .encodings
+            .values()
+            .map(|encoding| encoding.total_length as u64)
+            .sum();
+        *bytes_by_content_type
+            .entry(asset.content_type.clone())
+            .or_default() += size;
+        sizes.push(AssetSize {
+            key: key.clone(),
+            size,
+        });
+    }
+    sizes.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.key.cmp(&b.key)));
+    sizes.truncate(LARGEST_ASSETS);
+
+    Stats {
+        asset_count: state.assets.len() as u64,
+        total_bytes: bytes_by_content_type.values().sum(),
+        stored_bytes: state
+            .content_store
+            .blobs
+            .values()
+            .map(|blob| blob.content.len() as u64)
+            .sum(),
+        bytes_by_content_type: bytes_by_content_type.into_iter().collect(),
+        largest_assets: sizes,
+        heap_memory_bytes: heap_memory_bytes(),
+        stable_memory_bytes: stable_memory_bytes(),
+        cycles_balance: Nat::from(ic_cdk::api::canister_balance128()),
+        last_upgrade: state.last_upgrade,
+        module_hash: state.module_hash.clone(),
+    }
+}
@@ -16,6 +16,191 @@ pub struct StoreArg {
     pub content: ByteBuf,
     pub sha256: Option<ByteBuf>,
     pub aliased: Option<bool>,
+    /// Leaves the current visibility unchanged when omitted; new assets
+    /// default to public.
+    pub visibility: Option<Visibility>,
+    /// Deletes the asset this many seconds after the upload unless it is
+    /// pinned first. Leaves the current expiry unchanged when omitted.
+    pub ttl_seconds: Option<u64>,
+    /// Only store if the current `content_encoding` has this SHA-256.
+    pub if_match_sha256: Option<ByteBuf>,
+    /// Only store if the key does not exist yet.
+    pub if_none_exists: Option<bool>,
+    /// Serve the asset on the uncertified `raw.icp0.io` domain instead of
+    /// redirecting there. Leaves the current setting unchanged when omitted.
+    pub allow_raw_access: Option<bool>,
+    /// Name offered when the asset is downloaded. Leaves the current name
+    /// unchanged when omitted.
+    pub filename: Option<String>,
+}
+
+/// What `store` reports about the asset it just wrote.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StoreResult {
+    pub key: AssetKey,
+    /// Hash and size of the encoding that was uploaded.
+    pub sha256: ByteBuf,
+    pub size: u64,
+    pub content_type: String,
+    /// All encodings now stored under the key, e.g. `identity` and `gzip`.
+    pub variants: Vec<String>,
+    pub urls: AssetUrls,
+}
+
+/// The URLs an asset is served from.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AssetUrls {
+    /// Certified URL on the canister's `icp0.io` subdomain.
+    pub certified: String,
+    /// Uncertified URL on the canister's `raw.icp0.io` subdomain.
+    pub raw: String,
+    /// URL on the configured custom domain, if there is one.
+    pub custom_domain: Option<String>,
+}
+
+/// What an API key may be used for. A key needs `Read` or `Write`; any
+/// `Prefix` scopes limit it to asset keys under those prefixes.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ApiKeyScope {
+    Read,
+    Write,
+    Prefix(String),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CreateApiKeyArg {
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+    /// The key never expires when omitted.
+    pub ttl_seconds: Option<u64>,
+}
+
+/// An issued API key, without its secret.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ApiKeyInfo {
+    pub id: u64,
+    pub name: String,
+    /// Requests made with the key act on behalf of this principal.
+    pub owner: Principal,
+    pub scopes: Vec<ApiKeyScope>,
+    pub created: u64,
+    pub expires_at: Option<u64>,
+}
+
+/// Maps request paths to a redirect or another asset; see `routing`.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RoutingRule {
+    /// An exact path, or a prefix ending in `*`.
+    pub source: String,
+    /// A path or URL, which may end in `*` to append what the source's `*`
+    /// matched.
+    pub destination: String,
+    pub action: RuleAction,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum RuleAction {
+    /// Answer with a 301, 302, 307 or 308 pointing at the destination.
+    Redirect { status_code: u16 },
+    /// Serve the destination asset in place of the requested path.
+    Rewrite,
+}
+
+/// Security headers sent with served assets. In per-prefix overrides an
+/// omitted field keeps the inherited value and an empty one removes it.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SecurityHeaderPolicy {
+    pub strict_transport_security: Option<String>,
+    pub content_security_policy: Option<String>,
+    /// Sources allowed to embed assets, e.g. `'self' https://partner.example`;
+    /// sent as a CSP directive and, for `'none'` and `'self'`, as
+    /// `X-Frame-Options`.
+    pub frame_ancestors: Option<String>,
+    pub referrer_policy: Option<String>,
+    pub permissions_policy: Option<String>,
+    pub cross_origin_resource_policy: Option<String>,
+    pub cross_origin_embedder_policy: Option<String>,
+}
+
+/// How the Prometheus metrics endpoint is exposed.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct MetricsConfig {
+    /// Path metrics are served at; `None`, the default, disables the
+    /// endpoint. An unguessable path keeps it unlisted.
+    pub path: Option<String>,
+    /// When set, requests must send `Authorization: Bearer <token>` for a
+    /// token with this SHA-256.
+    pub bearer_token_sha256: Option<ByteBuf>,
+}
+
+/// Which cross-origin requests may read assets under a path prefix.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CorsPolicy {
+    /// Origins such as `https://app.example.com`, or `*` for any origin.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    /// How long browsers may cache a preflight response.
+    pub max_age_seconds: Option<u64>,
+}
+
+/// Who may read an asset.
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub enum Visibility {
+    /// Served to anyone through `http_request`.
+    #[default]
+    Public,
+    /// Only retrievable by the owner through `retrieve`.
+    OwnerOnly,
+    /// Retrievable by the owner and members of the named role.
+    Role(String),
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct QuotaConfig {
+    pub max_asset_size: Option<u64>,
+    pub max_total_bytes: Option<u64>,
+    pub max_asset_count: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RateLimitConfig {
+    pub capacity: u64,
+    pub refill_per_minute: u64,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct Usage {
+    pub total_bytes: u64,
+    pub asset_count: u64,
+    /// Bytes held by previous versions, included in `total_bytes`.
+    pub history_bytes: u64,
+}
+
+/// Storage and canister statistics for capacity planning.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Stats {
+    pub asset_count: u64,
+    /// Bytes of all encodings of all assets, before deduplication.
+    pub total_bytes: u64,
+    /// Bytes held by the deduplicated content store.
+    pub stored_bytes: u64,
+    pub bytes_by_content_type: Vec<(String, u64)>,
+    /// The largest assets by the size of all their encodings, largest first.
+    pub largest_assets: Vec<AssetSize>,
+    pub heap_memory_bytes: u64,
+    pub stable_memory_bytes: u64,
+    pub cycles_balance: Nat,
+    /// When the current module was installed or last upgraded.
+    pub last_upgrade: u64,
+    /// SHA-256 of the installed module; looked up shortly after an upgrade.
+    pub module_hash: Option<ByteBuf>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AssetSize {
+    pub key: AssetKey,
+    pub size: u64,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -26,26 +211,29 @@ pub struct Asset {
     pub headers: Option<HashMap<String, String>>,
     pub is_aliased: Option<bool>,
     pub allow_raw_access: Option<bool>,
+    /// The principal that first stored this key; only it may modify it.
+    pub owner: Principal,
+    pub created: u64,
+    pub visibility: Visibility,
+    /// When the expiry sweeper deletes this asset; `None` keeps it forever.
+    pub expires_at: Option<u64>,
+    /// Incremented on every store or rollback; see `list_versions`.
+    pub version: u64,
+    /// Original filename, sent in `Content-Disposition`.
+    pub filename: Option<String>,
 }
-impl Asset {
-    fn get_headers_for_asset(&self, enc_name: &str, cert_version: u16) -> HashMap<String, String> {
-        let mut headers = HashMap::new();
-        headers.insert("Content-Type".to_string(), self.content_type.clone());
-        if let Some(encoding) = self.encodings.get(enc_name) {
-            headers.insert(
-                "Content-Length".to_string(),
-                encoding.total_length.to_string(),
-            );
-        }
-        headers.insert(
-            "Strict-Transport-Security".to_string(),
-            "max-age=31536000; includeSubDomains".to_string(),
-        );
-        headers.insert("X-Frame-Options".to_string(), "DENY".to_string());
-        headers.insert("X-Content-Type-Options".to_string(), "nosniff".to_string());
-        headers
-    }
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct VersionInfo {
+    pub version: u64,
+    pub content_type: String,
+    /// Hash and size of the identity encoding, if the version has one.
+    pub sha256: Option<ByteBuf>,
+    pub size: u64,
+    pub modified: u64,
+    pub current: bool,
 }
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct AssetEncoding {
     pub modified: u64,
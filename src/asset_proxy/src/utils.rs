@@ -1,5 +1,35 @@
 // src/utils.rs
-use percent_encoding::percent_decode_str;
+use canister_error::Error;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+/// Longest asset key accepted from callers.
+pub const MAX_KEY_LENGTH: usize = 1024;
+
+/// Longest filename accepted from callers.
+pub const MAX_FILENAME_LENGTH: usize = 255;
+
+/// Characters RFC 5987 allows unencoded in an extended parameter value.
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+/// Characters left unencoded when an asset key is used as a URL path.
+const PATH: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
 
 pub fn url_decode(url: &str) -> Result<String, String> {
     percent_decode_str(url)
@@ -7,3 +37,135 @@ pub fn url_decode(url: &str) -> Result<String, String> {
         .map(|s| s.to_string())
         .map_err(|e| e.to_string())
 }
+
+/// The time `ttl_seconds` after `now`, in nanoseconds, rejecting TTLs that
+/// would overflow.
+pub fn expiry_time(now: u64, ttl_seconds: u64) -> Result<u64, Error> {
+    ttl_seconds
+        .checked_mul(1_000_000_000)
+        .and_then(|ttl| now.checked_add(ttl))
+        .ok_or_else(|| Error::invalid_argument("ttl_seconds", "is too large"))
+}
+
+/// Percent-encodes an asset key for use as a URL path.
+pub fn url_encode_path(key: &str) -> String {
+    utf8_percent_encode(key, PATH).to_string()
+}
+
+/// Rejects keys that could not be served back over HTTP unambiguously.
+pub fn validate_key(key: &str) -> Result<(), Error> {
+    if !key.starts_with('/') {
+        return Err(Error::invalid_argument("key", "must start with '/'"));
+    }
+    if key.len() > MAX_KEY_LENGTH {
+        return Err(Error::invalid_argument(
+            "key",
+            format!(
+                "is {} bytes long, the maximum is {}",
+                key.len(),
+                MAX_KEY_LENGTH
+            ),
+        ));
+    }
+    if key
+        .chars()
+        .any(|c| c.is_control() || c.is_whitespace() || c == '?' || c == '#')
+    {
+        return Err(Error::invalid_argument(
+            "key",
+            format!("{:?} contains invalid characters", key),
+        ));
+    }
+    if key
+        .split('/')
+        .any(|segment| segment == "." || segment == "..")
+    {
+        return Err(Error::invalid_argument(
+            "key",
+            format!("{:?} contains relative path segments", key),
+        ));
+    }
+    // Certified paths are addressed by segment, which has no room for empty ones
+    if key.contains("//") {
+        return Err(Error::invalid_argument(
+            "key",
+            format!("{:?} contains an empty path segment", key),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether `path` is at or below `prefix`, matching whole segments: `/cars`
+/// covers `/cars` and `/cars/a.png` but not `/carsharing`, while `/cars/`
+/// covers only the paths below it.
+pub fn is_under(path: &str, prefix: &str) -> bool {
+    if prefix.ends_with('/') {
+        return path.starts_with(prefix);
+    }
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Value of the first header called `name`, compared case-insensitively.
+pub fn header_value<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Rejects filenames that are empty, overlong or contain path separators.
+pub fn validate_filename(filename: &str) -> Result<(), Error> {
+    if filename.is_empty() || filename.len() > MAX_FILENAME_LENGTH {
+        return Err(Error::invalid_argument(
+            "filename",
+            format!("must be between 1 and {} bytes long", MAX_FILENAME_LENGTH),
+        ));
+    }
+    if filename
+        .chars()
+        .any(|c| c.is_control() || c == '/' || c == '\\')
+    {
+        return Err(Error::invalid_argument(
+            "filename",
+            format!("{:?} contains invalid characters", filename),
+        ));
+    }
+    Ok(())
+}
+
+/// A `Content-Disposition` value offering `filename`, with an ASCII
+/// fallback and the exact name RFC 5987-encoded for clients that support it.
+pub fn content_disposition(attachment: bool, filename: &str) -> String {
+    let disposition = if attachment { "attachment" } else { "inline" };
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition,
+        fallback,
+        utf8_percent_encode(filename, ATTR_CHAR)
+    )
+}
+
+/// Splits a request URL into its path and optional query string.
+pub fn split_url(url: &str) -> (&str, Option<&str>) {
+    match url.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (url, None),
+    }
+}
+
+/// Returns the decoded value of the first query parameter called `name`.
+pub fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    query?
+        .split('&')
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+        .find(|(param, _)| *param == name)
+        .and_then(|(_, value)| url_decode(value).ok())
+}
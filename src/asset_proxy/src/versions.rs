@@ -0,0 +1,257 @@
+// src/versions.rs
+//! Bounded history of previous asset contents, for listing and rollback.
+
+use crate::content_store::ContentStore;
+use crate::types::{Asset, AssetEncoding, AssetKey};
+use std::collections::{HashMap, VecDeque};
+
+/// Number of previous versions kept per key unless configured otherwise.
+pub const DEFAULT_MAX_VERSIONS: usize = 5;
+
+/// A snapshot of an asset's content as it was before being replaced.
+#[derive(Clone, Debug)]
+pub struct AssetVersion {
+    pub version: u64,
+    pub content_type: String,
+    pub encodings: HashMap<String, AssetEncoding>,
+}
+
+/// Previous versions per key, oldest first. Snapshots hold references into
+/// the content store, so their bytes stay alive until they are pruned.
+#[derive(Clone, Debug)]
+pub struct VersionStore {
+    pub max_versions: usize,
+    history: HashMap<AssetKey, VecDeque<AssetVersion>>,
+}
+
+impl Default for VersionStore {
+    fn default() -> Self {
+        VersionStore {
+            max_versions: DEFAULT_MAX_VERSIONS,
+            history: HashMap::new(),
+        }
+    }
+}
+
+impl VersionStore {
+    /// Snapshots the current content of `asset` before it is replaced.
+    pub fn record(&mut self, key: &AssetKey, asset: &Asset, content_store: &mut ContentStore) {
+        if self.max_versions == 0 {
+            return;
+        }
+
+        for encoding in asset.encodings.values() {
+            content_store.retain(&encoding.sha256);
+        }
+        let versions = self.history.entry(key.clone()).or_default();
+        versions.push_back(AssetVersion {
+            version: asset.version,
+            content_type: asset.content_type.clone(),
+            encodings: asset.encodings.clone(),
+        });
+        while versions.len() > self.max_versions {
+            if let Some(pruned) = versions.pop_front() {
+                release(&pruned, content_store);
+            }
+        }
+    }
+
+    pub fn get(&self, key: &str, version: u64) -> Option<&AssetVersion> {
+        self.history
+            .get(key)?
+            .iter()
+            .find(|snapshot| snapshot.version == version)
+    }
+
+    pub fn list(&self, key: &AssetKey) -> impl Iterator<Item = &AssetVersion> {
+        self.history.get(key).into_iter().flatten()
+    }
+
+    /// Bytes held by the snapshots of `key`, counted like the assets
+    /// themselves: the length of every encoding.
+    pub fn size_of(&self, key: &str) -> u64 {
+        self.history
+            .get(key)
+            .into_iter()
+            .flatten()
+            .flat_map(|snapshot| snapshot.encodings.values())
+            .map(|encoding| encoding.total_length as u64)
+            .sum()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &AssetKey> {
+        self.history.keys()
+    }
+
+    /// Drops the oldest snapshot of `key`, returning whether there was one.
+    pub fn prune_oldest(&mut self, key: &str, content_store: &mut ContentStore) -> bool {
+        let Some(versions) = self.history.get_mut(key) else {
+            return false;
+        };
+        let Some(pruned) = versions.pop_front() else {
+            return false;
+        };
+        release(&pruned, content_store);
+        if versions.is_empty() {
+            self.history.remove(key);
+        }
+        true
+    }
+
+    /// Drops the whole history of a key.
+    pub fn remove(&mut self, key: &AssetKey, content_store: &mut ContentStore) {
+        for snapshot in self.history.remove(key).into_iter().flatten() {
+            release(&snapshot, content_store);
+        }
+    }
+
+    /// Changes how many versions are kept, pruning existing histories.
+    pub fn set_max_versions(&mut self, max_versions: usize, content_store: &mut ContentStore) {
+        self.max_versions = max_versions;
+        for versions in self.history.values_mut() {
+            while versions.len() > max_versions {
+                if let Some(pruned) = versions.pop_front() {
+                    release(&pruned, content_store);
+                }
+            }
+        }
+        self.history.retain(|_, versions| !versions.is_empty());
+    }
+}
+
+fn release(snapshot: &AssetVersion, content_store: &mut ContentStore) {
+    for encoding in snapshot.encodings.values() {
+        content_store.release(&encoding.sha256);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::State;
+    use crate::types::StoreArg;
+    use candid::Principal;
+    use serde_bytes::ByteBuf;
+    use sha2::{Digest, Sha256};
+
+    fn hash(content: &[u8]) -> [u8; 32] {
+        Sha256::digest(content).into()
+    }
+
+    fn ref_count(content_store: &ContentStore, content: &[u8]) -> usize {
+        content_store
+            .blobs
+            .get(&hash(content))
+            .map_or(0, |blob| blob.ref_count)
+    }
+
+    fn store(state: &mut State, content: &[u8]) {
+        let arg = StoreArg {
+            key: "/a.txt".to_string(),
+            content_type: "text/plain".to_string(),
+            content_encoding: "identity".to_string(),
+            content: ByteBuf::from(content.to_vec()),
+            sha256: None,
+            aliased: None,
+            visibility: None,
+            ttl_seconds: None,
+            if_match_sha256: None,
+            if_none_exists: None,
+            allow_raw_access: None,
+            filename: None,
+        };
+        state.store(arg, Principal::anonymous(), 0).unwrap();
+    }
+
+    #[test]
+    fn snapshots_hold_references_until_pruned() {
+        let mut state = State::default();
+        state.set_max_versions(2);
+        for content in [b"one", b"two", b"six"] {
+            store(&mut state, content);
+        }
+        assert_eq!(ref_count(&state.content_store, b"one"), 1);
+        assert_eq!(ref_count(&state.content_store, b"two"), 1);
+        assert_eq!(ref_count(&state.content_store, b"six"), 1);
+
+        store(&mut state, b"ten");
+        assert_eq!(ref_count(&state.content_store, b"one"), 0);
+        assert!(state.versions.get("/a.txt", 1).is_none());
+
+        state.set_max_versions(0);
+        assert_eq!(ref_count(&state.content_store, b"two"), 0);
+        assert_eq!(ref_count(&state.content_store, b"six"), 0);
+        assert_eq!(ref_count(&state.content_store, b"ten"), 1);
+    }
+
+    #[test]
+    fn rollback_keeps_the_restored_content() {
+        let mut state = State::default();
+        state.set_max_versions(1);
+        store(&mut state, b"old");
+        store(&mut state, b"new");
+
+        // Recording `new` prunes the snapshot of `old` being restored
+        state
+            .rollback(&"/a.txt".to_string(), 1, Principal::anonymous(), 0)
+            .unwrap();
+        let asset = &state.assets["/a.txt"];
+        assert_eq!(asset.version, 3);
+        assert_eq!(&*asset.encodings["identity"].content_chunks[0], b"old");
+        assert_eq!(ref_count(&state.content_store, b"old"), 1);
+        assert_eq!(ref_count(&state.content_store, b"new"), 1);
+        assert!(state.versions.get("/a.txt", 1).is_none());
+        assert!(state.versions.get("/a.txt", 2).is_some());
+    }
+
+    #[test]
+    fn history_is_charged_and_pruned_to_the_quota() {
+        let mut state = State::default();
+        state.quotas.config.max_total_bytes = Some(25);
+        for content in [b"version 01", b"version 02", b"version 03"] {
+            store(&mut state, content);
+        }
+
+        // The current 10 bytes leave room for a single snapshot
+        let usage = state.get_usage(&Principal::anonymous());
+        assert_eq!(usage.total_bytes, 20);
+        assert_eq!(usage.history_bytes, 10);
+        assert_eq!(state.versions.list(&"/a.txt".to_string()).count(), 1);
+        let stored: usize = state
+            .content_store
+            .blobs
+            .values()
+            .map(|blob| blob.content.len())
+            .sum();
+        assert_eq!(stored, 20);
+
+        state.quotas.config.max_total_bytes = Some(10);
+        store(&mut state, b"version 04");
+        assert_eq!(state.get_usage(&Principal::anonymous()).total_bytes, 10);
+        assert_eq!(state.versions.list(&"/a.txt".to_string()).count(), 0);
+        assert_eq!(ref_count(&state.content_store, b"version 03"), 0);
+
+        state.set_max_versions(0);
+        state.quotas.config.max_total_bytes = None;
+        store(&mut state, b"version 05");
+        state.set_max_versions(5);
+        store(&mut state, b"version 06");
+        state
+            .delete(&"/a.txt".to_string(), Principal::anonymous())
+            .unwrap();
+        assert_eq!(state.get_usage(&Principal::anonymous()).total_bytes, 0);
+    }
+
+    #[test]
+    fn removing_a_key_releases_its_history() {
+        let mut state = State::default();
+        store(&mut state, b"one");
+        store(&mut state, b"two");
+        state
+            .versions
+            .remove(&"/a.txt".to_string(), &mut state.content_store);
+        assert_eq!(ref_count(&state.content_store, b"one"), 0);
+        assert_eq!(ref_count(&state.content_store, b"two"), 1);
+        assert_eq!(state.versions.list(&"/a.txt".to_string()).count(), 0);
+    }
+}
@@ -0,0 +1,90 @@
+// src/lib.rs
+//! The error type returned by every fallible endpoint of asset_proxy and
+//! provision, so clients can handle errors from both canisters with one
+//! Candid type.
+
+use candid::{CandidType, Deserialize};
+use serde_bytes::ByteBuf;
+use std::fmt;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum Error {
+    /// The named asset, version or record does not exist.
+    NotFound(String),
+    /// The caller is not allowed to perform the operation.
+    Unauthorized(String),
+    /// The content does not match the SHA-256 the caller provided.
+    HashMismatch {
+        expected: ByteBuf,
+        actual: ByteBuf,
+    },
+    /// The operation would exceed the caller's storage quota.
+    QuotaExceeded(String),
+    /// The caller has exhausted its rate limit; retry later.
+    RateLimited(String),
+    /// A precondition did not hold; carries the current content hash, if any.
+    Conflict {
+        current_sha256: Option<ByteBuf>,
+    },
+    /// A record with the same ID already exists.
+    AlreadyExists(String),
+    InvalidArgument {
+        field: String,
+        reason: String,
+    },
+    /// Something outside the caller's control failed.
+    Internal(String),
+}
+
+impl Error {
+    pub fn invalid_argument(field: &str, reason: impl Into<String>) -> Self {
+        Error::InvalidArgument {
+            field: field.to_string(),
+            reason: reason.into(),
+        }
+    }
+
+    /// The HTTP status code matching this error.
+    pub fn status_code(&self) -> u16 {
+        match self {
+            Error::NotFound(_) => 404,
+            Error::Unauthorized(_) => 403,
+            Error::HashMismatch { .. } | Error::InvalidArgument { .. } => 400,
+            Error::QuotaExceeded(_) => 413,
+            Error::RateLimited(_) => 429,
+            Error::Conflict { .. } | Error::AlreadyExists(_) => 409,
+            Error::Internal(_) => 500,
+        }
+    }
+
+    /// The variant name, used to label error metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::NotFound(_) => "NotFound",
+            Error::Unauthorized(_) => "Unauthorized",
+            Error::HashMismatch { .. } => "HashMismatch",
+            Error::QuotaExceeded(_) => "QuotaExceeded",
+            Error::RateLimited(_) => "RateLimited",
+            Error::Conflict { .. } => "Conflict",
+            Error::AlreadyExists(_) => "AlreadyExists",
+            Error::InvalidArgument { .. } => "InvalidArgument",
+            Error::Internal(_) => "Internal",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound(what) => write!(f, "{} not found", what),
+            Error::Unauthorized(msg)
+            | Error::QuotaExceeded(msg)
+            | Error::RateLimited(msg)
+            | Error::Internal(msg) => write!(f, "{}", msg),
+            Error::HashMismatch { .. } => write!(f, "SHA-256 hash mismatch"),
+            Error::Conflict { .. } => write!(f, "Precondition failed"),
+            Error::AlreadyExists(what) => write!(f, "{} already exists", what),
+            Error::InvalidArgument { field, reason } => write!(f, "Invalid {}: {}", field, reason),
+        }
+    }
+}
@@ -1,46 +1,241 @@
 // src/lib.rs
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
 
-use candid::Principal;
-use ic_cdk::api::caller;
+use candid::{Nat, Principal};
+use ic_cdk::api::call::{
+    accept_message, arg_data, arg_data_raw_size, method_name, ArgDecoderConfig,
+};
+use ic_cdk::api::management_canister::main::{canister_info, CanisterInfoRequest};
+use ic_cdk::api::{caller, is_controller, time};
 use ic_cdk::storage;
-use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
+use ic_cdk_macros::{init, inspect_message, post_upgrade, pre_upgrade, query, update};
+use ic_cdk_timers::set_timer;
+use percent_encoding::percent_decode_str;
+use serde_bytes::ByteBuf;
 mod rc_bytes;
 mod types;
-use types::{Asset, CarCollection};
+use canister_error::Error;
+use types::{Asset, AssetSize, CarCollection, Stats};
+
+/// Upper bound on an encoded `add_asset` argument, which carries content.
+const MAX_ASSET_ARG_SIZE: usize = 2 * 1024 * 1024;
+/// Upper bound on the encoded arguments of any other update.
+const MAX_ARG_SIZE: usize = 64 * 1024;
+/// Longest asset ID or asset reference accepted from callers.
+const MAX_ID_LENGTH: usize = 1024;
+/// How many of the largest assets `stats` lists.
+const LARGEST_ASSETS: usize = 10;
+const WASM_PAGE_SIZE: u64 = 65536;
 
 // Global state for assets and collections
 thread_local! {
     static ASSETS: RefCell<HashMap<String, Asset>> = RefCell::default();
     static COLLECTIONS: RefCell<HashMap<u64, CarCollection>> = RefCell::default();
+    // The asset_proxy canister whose uploads collections reference
+    static ASSET_PROXY: RefCell<Option<Principal>> = const { RefCell::new(None) };
+    // When the current module was installed or last upgraded
+    static LAST_UPGRADE: Cell<u64> = const { Cell::new(0) };
+    // SHA-256 of the installed module, once `canister_info` has answered
+    static MODULE_HASH: RefCell<Option<ByteBuf>> = const { RefCell::new(None) };
 }
 
 #[init]
 fn init() {
     // Initialization logic can be added here if needed
+    record_install();
 }
 
 #[pre_upgrade]
 fn pre_upgrade() {
     let collections = COLLECTIONS.with(|c| c.borrow().clone());
     let assets = ASSETS.with(|a| a.borrow().clone());
-    storage::stable_save((collections, assets)).expect("Failed to save stable state");
+    let asset_proxy = ASSET_PROXY.with(|p| *p.borrow());
+    storage::stable_save((collections, assets, asset_proxy)).expect("Failed to save stable state");
 }
 
 #[post_upgrade]
 fn post_upgrade() {
-    let (collections, assets): (HashMap<u64, CarCollection>, HashMap<String, Asset>) =
-        storage::stable_restore().expect("Failed to restore stable state");
+    // `asset_proxy` is absent in state saved by older versions and restores as `None`
+    let (collections, assets, asset_proxy): (
+        HashMap<u64, CarCollection>,
+        HashMap<String, Asset>,
+        Option<Principal>,
+    ) = storage::stable_restore().expect("Failed to restore stable state");
     COLLECTIONS.with(|c| *c.borrow_mut() = collections);
     ASSETS.with(|a| *a.borrow_mut() = assets);
+    ASSET_PROXY.with(|p| *p.borrow_mut() = asset_proxy);
+    record_install();
+}
+
+/// Records when the current module was installed. Its hash can only be
+/// looked up with an inter-canister call, which `init` and `post_upgrade`
+/// cannot make, so that happens in a timer right after.
+fn record_install() {
+    LAST_UPGRADE.with(|t| t.set(time()));
+    set_timer(Duration::ZERO, || ic_cdk::spawn(fetch_module_hash()));
+}
+
+async fn fetch_module_hash() {
+    let arg = CanisterInfoRequest {
+        canister_id: ic_cdk::id(),
+        num_requested_changes: None,
+    };
+    // On failure `stats` reports no hash until the next upgrade
+    if let Ok((info,)) = canister_info(arg).await {
+        MODULE_HASH.with(|h| *h.borrow_mut() = info.module_hash.map(ByteBuf::from));
+    }
+}
+
+#[inspect_message]
+fn inspect_message() {
+    let caller = caller();
+    if caller == Principal::anonymous() {
+        return;
+    }
+
+    // Reject ingress that would fail anyway before it costs cycles
+    let arg_size = arg_data_raw_size();
+    let allowed = match method_name().as_str() {
+        "add_asset" => {
+            arg_size <= MAX_ASSET_ARG_SIZE && {
+                let (asset,): (Asset,) = arg_data(ArgDecoderConfig::default());
+                validate_id("id", &asset.id).is_ok()
+            }
+        }
+        "add_car_collection" => {
+            arg_size <= MAX_ARG_SIZE && {
+                let (car,): (CarCollection,) = arg_data(ArgDecoderConfig::default());
+                car.owner == caller && validate_collection(&car).is_ok()
+            }
+        }
+        "set_asset_proxy" => arg_size <= MAX_ARG_SIZE && is_controller(&caller),
+        _ => arg_size <= MAX_ARG_SIZE,
+    };
+    if allowed {
+        accept_message();
+    }
+}
+
+/// Rejects empty, overlong or whitespace-containing IDs.
+fn validate_id(field: &str, id: &str) -> Result<(), Error> {
+    if id.is_empty() || id.len() > MAX_ID_LENGTH {
+        return Err(Error::invalid_argument(
+            field,
+            format!("must be between 1 and {} bytes long", MAX_ID_LENGTH),
+        ));
+    }
+    if id.chars().any(|c| c.is_control() || c.is_whitespace()) {
+        return Err(Error::invalid_argument(
+            field,
+            format!("{:?} contains invalid characters", id),
+        ));
+    }
+    Ok(())
+}
+
+/// All asset references of a collection; the logo is optional.
+fn asset_references(car: &CarCollection) -> impl Iterator<Item = &String> {
+    std::iter::once(&car.logo)
+        .filter(|logo| !logo.is_empty())
+        .chain(&car.images)
+        .chain(&car.documents)
+}
+
+fn validate_collection(car: &CarCollection) -> Result<(), Error> {
+    asset_references(car).try_for_each(|reference| validate_id("asset reference", reference))
+}
+
+/// Domains asset_proxy serves uploads from, under `<canister>.` or
+/// `<canister>.raw.`.
+const ASSET_PROXY_DOMAINS: &[&str] = &["icp0.io", "ic0.app", "localhost"];
+
+/// Turns an asset reference into a key of `asset_proxy`, or `None` if it
+/// points elsewhere. References are either keys or the URLs `store`
+/// returns, i.e. `https://<canister>.icp0.io<key>` with the key
+/// percent-encoded.
+fn asset_key(reference: &str, asset_proxy: &Principal) -> Option<String> {
+    if reference.starts_with('/') {
+        return Some(reference.to_string());
+    }
+    let (_, rest) = reference.split_once("://")?;
+    let (host, path) = rest
+        .find('/')
+        .map_or((rest, "/"), |start| rest.split_at(start));
+    if !is_asset_proxy_host(host, asset_proxy) {
+        return None;
+    }
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    Some(percent_decode_str(path).decode_utf8_lossy().into_owned())
+}
+
+/// Whether `host`, possibly with a port, is one of asset_proxy's URLs.
+fn is_asset_proxy_host(host: &str, asset_proxy: &Principal) -> bool {
+    let name = host.split(':').next().unwrap_or_default();
+    let Some(domain) = name.strip_prefix(&format!("{}.", asset_proxy.to_text())) else {
+        return false;
+    };
+    let domain = domain.strip_prefix("raw.").unwrap_or(domain);
+    ASSET_PROXY_DOMAINS.contains(&domain)
+}
+
+/// Clears the expiry of the owner's uploads a collection references, so
+/// drafts that end up in a committed collection are not swept by
+/// asset_proxy. References to anything else are left alone.
+async fn pin_collection_assets(car: &CarCollection) -> Result<(), Error> {
+    let Some(asset_proxy) = ASSET_PROXY.with(|p| *p.borrow()) else {
+        return Ok(());
+    };
+    let keys: Vec<String> = asset_references(car)
+        .filter_map(|r| asset_key(r, &asset_proxy))
+        .collect();
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let (result,): (Result<(), Error>,) =
+        ic_cdk::call(asset_proxy, "pin_assets", (keys, Some(car.owner)))
+            .await
+            .map_err(|(code, msg)| {
+                Error::Internal(format!("Failed to pin assets ({:?}): {}", code, msg))
+            })?;
+    result
+}
+
+#[update]
+fn set_asset_proxy(canister: Option<Principal>) -> Result<(), Error> {
+    if !is_controller(&caller()) {
+        return Err(Error::Unauthorized(
+            "Only controllers can perform this action".to_string(),
+        ));
+    }
+    ASSET_PROXY.with(|p| *p.borrow_mut() = canister);
+    Ok(())
+}
+
+#[query]
+fn get_asset_proxy() -> Option<Principal> {
+    ASSET_PROXY.with(|p| *p.borrow())
 }
 
 #[update]
-fn add_car_collection(car: CarCollection) -> Result<(), String> {
+async fn add_car_collection(car: CarCollection) -> Result<(), Error> {
+    if car.owner != caller() {
+        return Err(Error::Unauthorized(
+            "Collection owner must be the caller".to_string(),
+        ));
+    }
+    validate_collection(&car)?;
+    if COLLECTIONS.with(|collections| collections.borrow().contains_key(&car.id)) {
+        return Err(Error::AlreadyExists(format!("Collection {}", car.id)));
+    }
+
+    pin_collection_assets(&car).await?;
+
+    // Another call may have added the same ID while the assets were pinned
     COLLECTIONS.with(|collections| {
         if collections.borrow().contains_key(&car.id) {
-            Err("Collection with this ID already exists".to_string())
+            Err(Error::AlreadyExists(format!("Collection {}", car.id)))
         } else {
             collections.borrow_mut().insert(car.id, car);
             Ok(())
@@ -54,11 +249,12 @@ fn get_car_collection(id: u64) -> Option<CarCollection> {
 }
 
 #[update]
-fn add_asset(mut asset: Asset) -> Result<(), String> {
+fn add_asset(mut asset: Asset) -> Result<(), Error> {
+    validate_id("id", &asset.id)?;
     asset.owner = caller();
     ASSETS.with(|assets| {
         if assets.borrow().contains_key(&asset.id) {
-            Err("Asset with this ID already exists".to_string())
+            Err(Error::AlreadyExists(format!("Asset {}", asset.id)))
         } else {
             assets.borrow_mut().insert(asset.id.clone(), asset);
             Ok(())
@@ -71,9 +267,61 @@ fn get_asset(id: String) -> Option<Asset> {
     ASSETS.with(|assets| assets.borrow().get(&id).cloned())
 }
 
-// Export the candid interface
-use ic_cdk_macros::query as export_query;
+fn heap_memory_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
 
+/// Collection and asset counts, storage and memory usage, cycles balance
+/// and module details for capacity planning.
+#[query]
+fn stats() -> Stats {
+    let mut bytes_by_content_type: BTreeMap<String, u64> = BTreeMap::new();
+    let mut sizes: Vec<AssetSize> = ASSETS.with(|assets| {
+        assets
+            .borrow()
+            .values()
+            .map(|asset| {
+                let size: u64 = asset
+                    .encodings
+                    .values()
+                    .map(|encoding| encoding.total_length as u64)
+                    .sum();
+                *bytes_by_content_type
+                    .entry(asset.content_type.clone())
+                    .or_default() += size;
+                AssetSize {
+                    id: asset.id.clone(),
+                    size,
+                }
+            })
+            .collect()
+    });
+    sizes.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.id.cmp(&b.id)));
+    let asset_count = sizes.len() as u64;
+    sizes.truncate(LARGEST_ASSETS);
+
+    Stats {
+        collection_count: COLLECTIONS.with(|c| c.borrow().len() as u64),
+        asset_count,
+        total_bytes: bytes_by_content_type.values().sum(),
+        bytes_by_content_type: bytes_by_content_type.into_iter().collect(),
+        largest_assets: sizes,
+        heap_memory_bytes: heap_memory_bytes(),
+        stable_memory_bytes: ic_cdk::api::stable::stable_size() * WASM_PAGE_SIZE,
+        cycles_balance: Nat::from(ic_cdk::api::canister_balance128()),
+        last_upgrade: LAST_UPGRADE.with(|t| t.get()),
+        module_hash: MODULE_HASH.with(|h| h.borrow().clone()),
+    }
+}
+
+// Export the candid interface
 ic_cdk::export_candid!();
 
 #[query(name = "__get_candid_interface_tmp_hack")]
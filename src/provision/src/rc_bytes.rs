@@ -5,7 +5,7 @@ use candid::{
     types::{Serializer as CandidSerializer, Type, TypeInner},
     CandidType, Deserialize as CandidDeserialize,
 };
-use serde::{Serialize, Serializer as SerdeSerializer, Deserialize, Deserializer};
+use serde::{Serialize, Serializer as SerdeSerializer, Deserializer};
 use serde_bytes::ByteBuf;
 use std::convert::AsRef;
 use std::ops::Deref;
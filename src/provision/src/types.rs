@@ -1,9 +1,8 @@
 // src/types.rs
-use candid::{CandidType, Deserialize as CandidDeserialize, Principal};
-use serde::{Deserialize, Serialize};
+use candid::{CandidType, Deserialize as CandidDeserialize, Nat, Principal};
+use serde::Serialize;
 use serde_bytes::ByteBuf;
 use std::collections::BTreeMap;
-use std::rc::Rc;
 
 use crate::rc_bytes::RcBytes;
 
@@ -43,3 +42,27 @@ pub struct AssetEncoding {
     pub sha256: [u8; 32],
 }
 
+/// Storage and canister statistics for capacity planning.
+#[derive(CandidType, CandidDeserialize, Clone)]
+pub struct Stats {
+    pub collection_count: u64,
+    pub asset_count: u64,
+    /// Bytes of all encodings of all assets.
+    pub total_bytes: u64,
+    pub bytes_by_content_type: Vec<(String, u64)>,
+    /// The largest assets by the size of all their encodings, largest first.
+    pub largest_assets: Vec<AssetSize>,
+    pub heap_memory_bytes: u64,
+    pub stable_memory_bytes: u64,
+    pub cycles_balance: Nat,
+    /// When the current module was installed or last upgraded.
+    pub last_upgrade: u64,
+    /// SHA-256 of the installed module; looked up shortly after an upgrade.
+    pub module_hash: Option<ByteBuf>,
+}
+
+#[derive(CandidType, CandidDeserialize, Clone)]
+pub struct AssetSize {
+    pub id: String,
+    pub size: u64,
+}